@@ -0,0 +1,116 @@
+use std::fs::{DirBuilder, File, OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Replaces the file at `path` with `contents` so that a crash or a full disk leaves either the old
+/// or the new file, never a truncated one: the contents go to a temp file next to it, which is synced
+/// and then renamed over it. The file keeps its permissions.
+pub fn write_atomic(path: &str, contents: &[u8]) -> Result<(), std::io::Error> {
+    replace_file(path, contents, None)
+}
+
+/// Like write_atomic, but the file is only readable and writable by its owner, whatever its
+/// permissions were before. For files that hold secrets.
+pub fn write_atomic_private(path: &str, contents: &[u8]) -> Result<(), std::io::Error> {
+    replace_file(path, contents, Some(0o600))
+}
+
+fn replace_file(path: &str, contents: &[u8], mode: Option<u32>) -> Result<(), std::io::Error> {
+    let temp = format!("{}.tmp.{}", path, std::process::id());
+    let written = write_synced(&temp, contents, path, mode).and_then(|_| std::fs::rename(&temp, path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+        return written;
+    }
+
+    // sync the directory too, otherwise the rename itself can be lost
+    let directory = Path::new(path).parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    File::open(directory)?.sync_all()
+}
+
+fn write_synced(temp: &str, contents: &[u8], path: &str, mode: Option<u32>) -> Result<(), std::io::Error> {
+    // a private file is never readable by anyone else, not even before its permissions are set
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(mode.unwrap_or(0o666)).open(temp)?;
+    match mode {
+        Some(mode) => file.set_permissions(Permissions::from_mode(mode))?,
+        None => {
+            if let Ok(metadata) = std::fs::metadata(path) {
+                file.set_permissions(metadata.permissions())?;
+            }
+        }
+    }
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// The backups of `path`, newest first: `<path>.1` to `<path>.<count>`.
+pub fn backup_paths(path: &str, count: usize) -> Vec<String> {
+    (1..=count).map(|n| format!("{}.{}", path, n)).collect()
+}
+
+// the paths write_with_backups has written in this run
+static BACKED_UP: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Like write_atomic, but first keeps the current file as `<path>.1`, moving the older backups up
+/// one and dropping the oldest so there are at most `count`. That only happens the first time a path
+/// is written in a run, so a command that saves many times, like an upgrade, still leaves the file
+/// from before it as `.1` and the backups from earlier commands behind it.
+pub fn write_with_backups(path: &str, contents: &[u8], count: usize) -> Result<(), std::io::Error> {
+    let mut backed_up = BACKED_UP.lock().unwrap();
+    let first_write = !backed_up.iter().any(|p| p == path);
+    if first_write {
+        backed_up.push(path.to_string());
+    }
+    if count > 0 && first_write && Path::new(path).exists() {
+        let backups = backup_paths(path, count);
+        for pair in backups.windows(2).rev() {
+            if Path::new(&pair[0]).exists() {
+                std::fs::rename(&pair[0], &pair[1])?;
+            }
+        }
+        let _ = std::fs::remove_file(&backups[0]);
+        // a hard link, so the current file stays in place until the new one replaces it
+        std::fs::hard_link(path, &backups[0])?;
+    }
+    write_atomic(path, contents)
+}
+
+/// Creates a new directory in the system temp directory that only the current user can get into,
+/// for temp files nobody else may read, replace or plant a symlink in place of. The name is made
+/// up of `prefix`, the pid and the time, and it is never an existing directory or symlink.
+pub fn create_private_dir(prefix: &str) -> Result<PathBuf, std::io::Error> {
+    let mut attempt = 0;
+    loop {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        let path = std::env::temp_dir().join(format!("{}-{}-{}", prefix, std::process::id(), nanos));
+        match DirBuilder::new().mode(0o700).create(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempt < 10 => attempt += 1,
+            result => return result.map(|_| path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_up_once_per_run() {
+        let directory = create_private_dir("uspm-test-backups").unwrap();
+        let path = directory.join("packages.json").to_string_lossy().to_string();
+        let read = |path: &str| std::fs::read_to_string(path).ok();
+        std::fs::write(&path, "current").unwrap();
+        std::fs::write(format!("{}.1", path), "older").unwrap();
+        std::fs::write(format!("{}.2", path), "oldest").unwrap();
+
+        write_with_backups(&path, b"first", 2).unwrap();
+        write_with_backups(&path, b"second", 2).unwrap();
+        let files = (read(&path), read(&format!("{}.1", path)), read(&format!("{}.2", path)), read(&format!("{}.3", path)));
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(files, (Some("second".to_string()), Some("current".to_string()), Some("older".to_string()), None));
+    }
+}
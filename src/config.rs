@@ -1,29 +1,193 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::Read;
+use reqwest::Url;
 use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use crate::atomic::write_atomic_private;
+
+/// Where the config is read from and saved to.
+pub const CONFIG_PATH: &str = "/etc/uspm/config.json";
+
+/// Drop-in config fragments, read in lexical order after CONFIG_PATH. Each is a JSON object with
+/// just the settings it changes.
+pub const CONFIG_DIRECTORY: &str = "/etc/uspm/config.d";
+
+/// The version of the config file format. Files from before the `version` key are version 1.
+pub const CONFIG_VERSION: u64 = 2;
+
+// each migration upgrades a config, in its json form, from the version at its index + 1 to the next version
+const MIGRATIONS: [fn(&mut Value); 1] = [migrate_v1];
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
+  #[serde(default = "default_version")]
+  version: u64,
+  // mirrors of the unnamed default repository, from before repositories could be named
+  #[serde(default)]
   mirrors: Vec<String>,
+  #[serde(default)]
+  repositories: Vec<RepositoryConfig>,
   // timeout: u64,
   // max_retries: u64,
   // retry_delay: u64,
+  #[serde(default = "default_storage_location")]
   storage_location: String,
+  #[serde(default)]
   checksum: bool,
+  // how many package archives to download at the same time
+  #[serde(default = "default_parallel_downloads")]
+  parallel_downloads: usize,
+  #[serde(default)]
+  network: NetworkConfig,
+  // show the output of package scripts as they run, it is logged either way
+  #[serde(default)]
+  verbose: bool,
+  // the directory packages install into, / unless installing into another system
+  #[serde(default = "default_install_root")]
+  install_root: String,
+  #[serde(default)]
+  scripts: ScriptConfig,
+  // which layer set each setting, keyed by dotted key. only filled in by load_layered
+  #[serde(skip)]
+  origins: HashMap<String, String>,
+}
+
+/// How uspm reaches the mirrors: proxies, extra trusted certificates, a client certificate,
+/// and credentials for private mirrors.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct NetworkConfig {
+  // proxy used for http:// mirrors
+  #[serde(default)]
+  pub http_proxy: Option<String>,
+  // proxy used for https:// mirrors
+  #[serde(default)]
+  pub https_proxy: Option<String>,
+  // hosts that are reached directly along with their subdomains, optionally only on one port (host:port). * matches everything
+  #[serde(default)]
+  pub no_proxy: Vec<String>,
+  // paths to PEM bundles of extra certificate authorities to trust
+  #[serde(default)]
+  pub ca_certificates: Vec<String>,
+  // path to a PKCS#12 file with the client certificate and key to present to mirrors
+  #[serde(default)]
+  pub client_certificate: Option<String>,
+  // secret, like the mirror credentials, so the config file is only readable by root and they are masked when shown
+  #[serde(default)]
+  pub client_certificate_password: String,
+  // credentials for private mirrors, keyed by mirror url
+  #[serde(default)]
+  pub mirror_auth: HashMap<String, MirrorAuth>,
+}
+
+/// How package scripts are run.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScriptConfig {
+  // seconds a script may run before it is killed, 0 for no limit
+  #[serde(default = "default_script_timeout")]
+  pub timeout: u64,
+  // run scripts in their own mount, ipc and uts namespaces, where everything but install_root is read-only.
+  // that would protect nothing with install_root at /, so the two can't be combined
+  #[serde(default)]
+  pub sandbox: bool,
+}
+
+impl Default for ScriptConfig {
+  fn default() -> Self {
+    ScriptConfig {
+      timeout: default_script_timeout(),
+      sandbox: false,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum MirrorAuth {
+  Basic { username: String, password: String },
+  Bearer { token: String },
+}
+
+// files from before the version key
+fn default_version() -> u64 {
+  1
+}
+
+fn default_storage_location() -> String {
+  "/var/uspm/storage".to_string()
+}
+
+fn default_parallel_downloads() -> usize {
+  4
+}
+
+fn default_install_root() -> String {
+  "/".to_string()
+}
+
+fn default_script_timeout() -> u64 {
+  600
+}
+
+fn default_enabled() -> bool {
+  true
+}
+
+/// Name of the repository made up of the plain `mirrors` list.
+pub const DEFAULT_REPOSITORY: &str = "default";
+
+/// A named repository. Its mirrors are interchangeable copies of it, tried in order until one responds.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RepositoryConfig {
+  pub name: String,
+  pub mirrors: Vec<String>,
+  // when several repositories have the same package, the one with the highest priority wins
+  #[serde(default)]
+  pub priority: i32,
+  #[serde(default = "default_enabled")]
+  pub enabled: bool,
+  // path to a keyring the repo file's detached signature (repo.json.sig) has to verify against
+  #[serde(default)]
+  pub signing_key: Option<String>,
+  // the channel and architecture, if set, are path segments after the mirror url: <mirror>/<channel>/<arch>
+  #[serde(default)]
+  pub channel: Option<String>,
+  #[serde(default)]
+  pub arch: Option<String>,
+}
+
+impl RepositoryConfig {
+  /// The url of the repository on one of its mirrors, which repo.json and the packages are under.
+  pub fn base_url(&self, mirror: &str) -> String {
+    let mut url = mirror.trim_end_matches('/').to_string();
+    for segment in [&self.channel, &self.arch].into_iter().flatten() {
+      url = url + "/" + segment;
+    }
+    url
+  }
 }
 
 impl Config {
   pub fn default() -> Self {
     Config {
+      version: CONFIG_VERSION,
       mirrors: vec![
         "http://repo.wombatlinux.org".to_string(),
         "https://afroraydude.com/wl/repo".to_string(),
       ],
+      repositories: Vec::new(),
       // timeout: 10,
       // max_retries: 3,
       // retry_delay: 1,
-      storage_location: "/var/uspm/storage".to_string(),
-      checksum: false
+      storage_location: default_storage_location(),
+      checksum: false,
+      parallel_downloads: default_parallel_downloads(),
+      network: NetworkConfig::default(),
+      verbose: false,
+      install_root: default_install_root(),
+      scripts: ScriptConfig::default(),
+      origins: HashMap::new(),
     }
   }
 
@@ -31,10 +195,53 @@ impl Config {
     &self.mirrors
   }
 
+  /// Every enabled repository, including the default repository made up of `mirrors`,
+  /// from the highest priority to the lowest.
+  pub fn repositories(&self) -> Vec<RepositoryConfig> {
+    let mut repositories: Vec<RepositoryConfig> = self.repositories.iter()
+        .filter(|r| r.enabled)
+        .cloned()
+        .collect();
+    if !self.mirrors.is_empty() {
+      repositories.push(RepositoryConfig {
+        name: DEFAULT_REPOSITORY.to_string(),
+        mirrors: self.mirrors.clone(),
+        priority: 0,
+        enabled: true,
+        signing_key: None,
+        channel: None,
+        arch: None,
+      });
+    }
+    repositories.sort_by_key(|r| std::cmp::Reverse(r.priority));
+    repositories
+  }
+
+  pub fn parallel_downloads(&self) -> usize {
+    self.parallel_downloads
+  }
+
+  pub fn verbose(&self) -> bool {
+    self.verbose
+  }
+
+  pub fn install_root(&self) -> &String {
+    &self.install_root
+  }
+
+  pub fn scripts(&self) -> &ScriptConfig {
+    &self.scripts
+  }
+
+  pub fn network(&self) -> &NetworkConfig {
+    &self.network
+  }
+
   pub fn storage_location(&self) -> &String {
     &self.storage_location
   }
 
+  #[allow(dead_code)]
   pub fn set_storage_location(&mut self, storage_location: String) {
     self.storage_location = storage_location;
   }
@@ -47,22 +254,454 @@ impl Config {
     self.mirrors.retain(|m| m != &mirror);
   }
 
+  /// Looks up a setting by its dotted key, like `parallel_downloads` or `network.http_proxy`.
+  /// Passwords and tokens come back masked, since this is what gets shown.
+  pub fn get(&self, key: &str) -> Result<Value, std::io::Error> {
+    let config = masked(serde_json::to_value(self)?);
+    let path = key_path(&config, key);
+    path.iter()
+        .try_fold(&config, |node, segment| child(node, segment))
+        .cloned()
+        .ok_or_else(|| unknown_key(key))
+  }
+
+  /// Sets a setting by its dotted key. The value has to have the right type for the setting.
+  pub fn set(&mut self, key: &str, value: Value) -> Result<(), std::io::Error> {
+    let mut config = serde_json::to_value(&*self)?;
+    let path = key_path(&config, key);
+    let (last, parents) = path.split_last().unwrap();
+    let parent = parents.iter()
+        .try_fold(&mut config, |node, segment| child_mut(node, segment))
+        .ok_or_else(|| unknown_key(key))?;
+    match parent {
+      Value::Object(map) => {
+        map.insert(last.clone(), value);
+      }
+      _ => *child_mut(parent, last).ok_or_else(|| unknown_key(key))? = value,
+    }
+
+    let updated = Config::from_value(config, key)?;
+    // serde ignores keys it doesn't know, so a typo would otherwise be silently dropped
+    updated.get(key)?;
+    *self = updated;
+    Ok(())
+  }
+
+  /// Sets a setting from text, which is read as JSON and otherwise taken as a plain string,
+  /// so `storage_location /opt/uspm` and `parallel_downloads 8` both do what they look like.
+  pub fn set_str(&mut self, key: &str, value: &str) -> Result<(), std::io::Error> {
+    let set = serde_json::from_str(value).map_err(std::io::Error::from).and_then(|json| self.set(key, json));
+    // `storage_location 123` means the string, not the number
+    if set.is_err() {
+      self.set(key, Value::String(value.to_string()))?;
+    }
+    Ok(())
+  }
+
+  /// Resets a setting to its default, or removes it if it has none (a repository or mirror credentials, for example).
+  pub fn unset(&mut self, key: &str) -> Result<(), std::io::Error> {
+    let mut config = serde_json::to_value(&*self)?;
+    let defaults = serde_json::to_value(Config::default())?;
+    let path = key_path(&config, key);
+    let default = path.iter().try_fold(&defaults, |node, segment| child(node, segment)).cloned();
+    let (last, parents) = path.split_last().unwrap();
+    let parent = parents.iter()
+        .try_fold(&mut config, |node, segment| child_mut(node, segment))
+        .ok_or_else(|| unknown_key(key))?;
+    match (parent, default) {
+      (parent, Some(default)) => *child_mut(parent, last).ok_or_else(|| unknown_key(key))? = default,
+      (Value::Object(map), None) => {
+        map.remove(last).ok_or_else(|| unknown_key(key))?;
+      }
+      (Value::Array(list), None) => {
+        let index = last.parse::<usize>().ok().filter(|i| *i < list.len()).ok_or_else(|| unknown_key(key))?;
+        list.remove(index);
+      }
+      _ => return Err(unknown_key(key)),
+    }
+
+    *self = Config::from_value(config, key)?;
+    Ok(())
+  }
+
+  fn from_value(value: Value, key: &str) -> Result<Self, std::io::Error> {
+    serde_json::from_value(value)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid value for {}: {}", key, e)))
+  }
+
+  /// Checks the settings make sense together, so a bad edit is caught before it is saved.
+  pub fn validate(&self) -> Result<(), std::io::Error> {
+    let invalid = |message: String| Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message));
+
+    if self.version != CONFIG_VERSION {
+      return invalid(format!("version must be {}", CONFIG_VERSION));
+    }
+    if !self.storage_location.starts_with('/') {
+      return invalid(format!("storage_location must be an absolute path, not \"{}\"", self.storage_location));
+    }
+    if !self.install_root.starts_with('/') {
+      return invalid(format!("install_root must be an absolute path, not \"{}\"", self.install_root));
+    }
+    if self.scripts.sandbox && self.install_root.trim_end_matches('/').is_empty() {
+      return invalid("scripts.sandbox needs install_root to be a directory other than /, since everything outside of it is made read-only".to_string());
+    }
+    if self.parallel_downloads == 0 {
+      return invalid("parallel_downloads must be at least 1".to_string());
+    }
+
+    let mut names = Vec::new();
+    for repository in self.repositories.iter() {
+      if repository.name.is_empty() || repository.name.contains('/') {
+        return invalid(format!("Repository name \"{}\" must not be empty or contain /", repository.name));
+      }
+      if names.contains(&&repository.name) || (repository.name == DEFAULT_REPOSITORY && !self.mirrors.is_empty()) {
+        return invalid(format!("Repository {} is defined more than once", repository.name));
+      }
+      if repository.mirrors.is_empty() {
+        return invalid(format!("Repository {} has no mirrors", repository.name));
+      }
+      names.push(&repository.name);
+    }
+
+    let mirrors = self.mirrors.iter().chain(self.repositories.iter().flat_map(|r| r.mirrors.iter()));
+    let proxies = self.network.http_proxy.iter().chain(self.network.https_proxy.iter());
+    for url in mirrors.chain(proxies) {
+      if Url::parse(url).is_err() {
+        return invalid(format!("{} is not a valid url", url));
+      }
+    }
+    Ok(())
+  }
+
   pub fn save(&self) -> Result<(), std::io::Error> {
-    let mut file = File::create("/etc/uspm/config.json")?;
+    self.validate()?;
     let serialized = serde_json::to_string_pretty(self)?;
-    file.write_all(serialized.as_bytes())?;
-    Ok(())
+    write_atomic_private(CONFIG_PATH, serialized.as_bytes())
+  }
+
+  /// Parses the contents of a config file, migrating it if it is from an older version.
+  /// Settings that are left out get their defaults, and errors say at which line and column the problem is.
+  pub fn parse(contents: &str) -> Result<Self, std::io::Error> {
+    let invalid = |e: serde_json::Error| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    let mut config: Value = serde_json::from_str(contents).map_err(invalid)?;
+
+    let version = match config.get("version") {
+      Some(version) => version.as_u64().filter(|v| *v >= 1).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("version must be a number from 1 to {}", CONFIG_VERSION))
+      })?,
+      None => default_version(),
+    };
+    if version > CONFIG_VERSION {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+        format!("Config version {} is newer than this uspm understands ({})", version, CONFIG_VERSION)));
+    }
+    if version == CONFIG_VERSION {
+      // parsed straight from the text so type errors still point at a line and column
+      return serde_json::from_str(contents).map_err(invalid);
+    }
+
+    for migration in MIGRATIONS[version as usize - 1..].iter() {
+      migration(&mut config);
+    }
+    config["version"] = Value::from(CONFIG_VERSION);
+    serde_json::from_value(config).map_err(|e| match serde_json::from_str::<Config>(contents) {
+      // the problem is in the original file too, where it has a line and column
+      Err(located) => invalid(located),
+      Ok(_) => invalid(e),
+    })
   }
 
   pub fn load() -> Result<Self, std::io::Error> {
-    let mut file = File::open("/etc/uspm/config.json")?;
+    let mut file = File::open(CONFIG_PATH)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
-    let config: Config = serde_json::from_str(&contents)?;
-    Ok(config)
+    Config::parse(&contents).map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", CONFIG_PATH, e)))
+  }
+
+  /// Loads the config in layers, each one overriding the ones before it: CONFIG_PATH, the fragments in
+  /// CONFIG_DIRECTORY, `USPM_*` environment variables (`USPM_PARALLEL_DOWNLOADS`, `USPM_NETWORK_HTTP_PROXY`, ...)
+  /// and finally the `overrides` given on the command line. `version` is about the file format, so it is
+  /// never taken from the environment. Saving only ever writes CONFIG_PATH, so use `load_or_default` to change the config.
+  pub fn load_layered(overrides: &[(String, String)]) -> Result<Self, std::io::Error> {
+    let mut origins = HashMap::new();
+    let mut config = serde_json::to_value(Config::load_or_default()?)?;
+    // settings the file leaves out are defaults, so only the ones it spells out count as coming from it
+    let written: Value = serde_json::from_str(&std::fs::read_to_string(CONFIG_PATH)?)?;
+    record_origins(&mut origins, &written, "", CONFIG_PATH);
+
+    for path in config_fragments()? {
+      let fragment: Value = serde_json::from_str(&std::fs::read_to_string(&path)?)
+          .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path, e)))?;
+      if !fragment.is_object() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: must be a JSON object", path)));
+      }
+      merge(&mut config, &fragment);
+      record_origins(&mut origins, &fragment, "", &path);
+    }
+    let mut layered = Config::from_value(config, CONFIG_DIRECTORY)?;
+
+    for key in setting_keys(&serde_json::to_value(&layered)?, "").into_iter().filter(|key| key != "version") {
+      let variable = "USPM_".to_string() + &key.replace('.', "_").to_uppercase();
+      if let Ok(value) = std::env::var(&variable) {
+        layered.set_str(&key, &value).map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", variable, e)))?;
+        set_origin(&mut origins, &key, "environment ".to_string() + &variable);
+      }
+    }
+    for (key, value) in overrides {
+      layered.set_str(key, value)?;
+      set_origin(&mut origins, key, "--set".to_string());
+    }
+
+    layered.validate()?;
+    layered.origins = origins;
+    Ok(layered)
+  }
+
+  /// Every setting as a dotted key with its value, and the layer that set it. Secrets are masked as in `get`.
+  pub fn settings_with_origins(&self) -> Vec<(String, Value, String)> {
+    let config = masked(serde_json::to_value(self).unwrap());
+    settings(&config, "").into_iter().map(|(key, value)| {
+      // a setting comes from the layer that last set it or anything it is part of
+      let origin = self.origins.iter()
+          .filter(|(set, _)| key == **set || key.starts_with(&(set.to_string() + ".")))
+          .max_by_key(|(set, _)| set.len())
+          .map(|(_, origin)| origin.clone())
+          .unwrap_or_else(|| "default".to_string());
+      (key, value.clone(), origin)
+    }).collect()
+  }
+
+  /// Loads the config file. The default config is only used (and saved) when there is no config file yet,
+  /// a config file that can't be read or parsed is an error and is never replaced.
+  pub fn load_or_default() -> Result<Self, std::io::Error> {
+    match Config::load() {
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        let config = Config::default();
+        config.save()?;
+        Ok(config)
+      }
+      result => result,
+    }
+  }
+}
+
+// the json files in CONFIG_DIRECTORY in lexical order, none if it doesn't exist
+fn config_fragments() -> Result<Vec<String>, std::io::Error> {
+  let entries = match std::fs::read_dir(CONFIG_DIRECTORY) {
+    Ok(entries) => entries,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(e) => return Err(e),
+  };
+  let mut fragments = Vec::new();
+  for entry in entries {
+    let path = entry?.path();
+    if path.extension().is_some_and(|extension| extension == "json") {
+      fragments.push(path.to_string_lossy().to_string());
+    }
+  }
+  fragments.sort();
+  Ok(fragments)
+}
+
+// merges a fragment into the config, objects key by key and everything else (lists included) replacing what was there
+fn merge(config: &mut Value, fragment: &Value) {
+  match (config, fragment) {
+    (Value::Object(config), Value::Object(fragment)) => {
+      for (key, value) in fragment {
+        match config.get_mut(key) {
+          Some(existing) => merge(existing, value),
+          None => {
+            config.insert(key.clone(), value.clone());
+          }
+        }
+      }
+    }
+    (config, fragment) => *config = fragment.clone(),
+  }
+}
+
+// the dotted keys of every setting, lists count as one setting
+fn setting_keys(value: &Value, prefix: &str) -> Vec<String> {
+  settings(value, prefix).into_iter().map(|(key, _)| key).collect()
+}
+
+// every setting as its dotted key and value. keys with dots of their own can't always be looked up
+// again by the dotted key, so the values are collected along the way
+fn settings<'a>(value: &'a Value, prefix: &str) -> Vec<(String, &'a Value)> {
+  match value {
+    Value::Object(map) if !map.is_empty() => map.iter()
+        .flat_map(|(key, value)| settings(value, &join_key(prefix, key)))
+        .collect(),
+    _ => vec![(prefix.to_string(), value)],
+  }
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+  if prefix.is_empty() {
+    key.to_string()
+  } else {
+    prefix.to_string() + "." + key
   }
+}
 
-  pub fn to_string(&self) -> String {
-    serde_json::to_string_pretty(self).unwrap()
+// marks every setting in a layer as coming from it
+fn record_origins(origins: &mut HashMap<String, String>, layer: &Value, prefix: &str, origin: &str) {
+  for key in setting_keys(layer, prefix) {
+    set_origin(origins, &key, origin.to_string());
   }
-}
\ No newline at end of file
+}
+
+// a layer that sets a whole object overrides whatever set the settings inside it before
+fn set_origin(origins: &mut HashMap<String, String>, key: &str, origin: String) {
+  origins.retain(|set, _| !set.starts_with(&(key.to_string() + ".")));
+  origins.insert(key.to_string(), origin);
+}
+
+// version 1 configs had to spell out every setting, and were rejected if one was missing.
+// fill in whatever is missing with its default so the file reads the same as a new one
+fn migrate_v1(config: &mut Value) {
+  let defaults = serde_json::to_value(Config::default()).unwrap();
+  if let (Value::Object(config), Value::Object(defaults)) = (config, defaults) {
+    for (key, default) in defaults {
+      config.entry(key).or_insert(default);
+    }
+  }
+}
+
+fn unknown_key(key: &str) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::NotFound, format!("Unknown config key {}", key))
+}
+
+// a field of an object, or an element of an array by its index
+fn child<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+  match value {
+    Value::Object(map) => map.get(key),
+    Value::Array(list) => key.parse::<usize>().ok().and_then(|i| list.get(i)),
+    _ => None,
+  }
+}
+
+fn child_mut<'a>(value: &'a mut Value, key: &str) -> Option<&'a mut Value> {
+  match value {
+    Value::Object(map) => map.get_mut(key),
+    Value::Array(list) => key.parse::<usize>().ok().and_then(|i| list.get_mut(i)),
+    _ => None,
+  }
+}
+
+// splits a dotted key into the path through the config, keeping keys that have dots of their own
+// (the mirror urls in network.mirror_auth) in one piece
+fn key_path(config: &Value, key: &str) -> Vec<String> {
+  let mut path = Vec::new();
+  let mut node = Some(config);
+  let mut rest = key;
+  loop {
+    if node.and_then(|n| child(n, rest)).is_some() {
+      break;
+    }
+    match rest.split_once('.') {
+      Some((head, tail)) if node.and_then(|n| child(n, head)).is_some() => {
+        node = node.and_then(|n| child(n, head));
+        path.push(head.to_string());
+        rest = tail;
+      }
+      _ => break,
+    }
+  }
+  path.push(rest.to_string());
+  path
+}
+
+/// What a password or token is shown as.
+pub const MASKED_SECRET: &str = "********";
+
+// the config with every password and token that is set replaced by MASKED_SECRET
+fn masked(mut config: Value) -> Value {
+  let mask = |secret: &mut Value| {
+    if secret.as_str().is_some_and(|s| !s.is_empty()) {
+      *secret = Value::from(MASKED_SECRET);
+    }
+  };
+  mask(&mut config["network"]["client_certificate_password"]);
+  if let Some(Value::Object(mirror_auth)) = config["network"].get_mut("mirror_auth") {
+    for (key, value) in mirror_auth.values_mut().filter_map(|auth| auth.as_object_mut()).flatten() {
+      if key == "password" || key == "token" {
+        mask(value);
+      }
+    }
+  }
+  config
+}
+
+// shown with its secrets masked
+impl fmt::Display for Config {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let config = masked(serde_json::to_value(self).map_err(|_| fmt::Error)?);
+    write!(f, "{}", serde_json::to_string_pretty(&config).unwrap())
+  }
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn shows_secrets_masked() {
+    let mut config = Config::default();
+    config.network.client_certificate_password = "hunter2".to_string();
+    config.network.mirror_auth.insert("https://mirror.example.org".to_string(), MirrorAuth::Bearer { token: "abc".to_string() });
+    let shown = config.to_string();
+    assert!(!shown.contains("hunter2") && !shown.contains("abc"));
+    assert_eq!(config.get("network.client_certificate_password").unwrap(), MASKED_SECRET);
+    assert_eq!(config.get("network.mirror_auth.https://mirror.example.org").unwrap()["token"], MASKED_SECRET);
+    // an unset password is left empty, so it is clear that there isn't one
+    assert_eq!(Config::default().get("network.client_certificate_password").unwrap(), "");
+  }
+
+  #[test]
+  fn splits_keys_around_dotted_names() {
+    let config = serde_json::json!({
+      "repositories": [{ "priority": 0 }],
+      "network": { "mirror_auth": { "https://mirror.example.org": {} } },
+    });
+    assert_eq!(key_path(&config, "repositories.0.priority"), ["repositories", "0", "priority"]);
+    assert_eq!(key_path(&config, "network.mirror_auth.https://mirror.example.org"), ["network", "mirror_auth", "https://mirror.example.org"]);
+    assert_eq!(key_path(&config, "network.http_proxy"), ["network", "http_proxy"]);
+  }
+
+  #[test]
+  fn migrates_version_1_configs() {
+    let config = Config::parse(r#"{ "mirrors": ["http://mirror.example.org"], "storage_location": "/srv/uspm" }"#).unwrap();
+    assert_eq!(config.version, CONFIG_VERSION);
+    assert_eq!(config.mirrors, ["http://mirror.example.org"]);
+    assert_eq!(config.storage_location, "/srv/uspm");
+    assert_eq!(config.parallel_downloads, default_parallel_downloads());
+  }
+
+  #[test]
+  fn refuses_newer_configs() {
+    let newer = format!(r#"{{ "version": {} }}"#, CONFIG_VERSION + 1);
+    assert!(Config::parse(&newer).is_err());
+  }
+
+  #[test]
+  fn merges_objects_key_by_key_and_replaces_lists() {
+    let mut config = serde_json::json!({ "mirrors": ["a", "b"], "network": { "http_proxy": "http://proxy", "no_proxy": ["x"] } });
+    merge(&mut config, &serde_json::json!({ "mirrors": ["c"], "network": { "no_proxy": [] }, "verbose": true }));
+    assert_eq!(config, serde_json::json!({ "mirrors": ["c"], "network": { "http_proxy": "http://proxy", "no_proxy": [] }, "verbose": true }));
+  }
+
+  #[test]
+  fn later_layers_take_over_the_origin_of_what_they_set() {
+    let mut origins = HashMap::new();
+    record_origins(&mut origins, &serde_json::json!({ "network": { "http_proxy": "x", "no_proxy": [] }, "verbose": true }), "", "file");
+    record_origins(&mut origins, &serde_json::json!({ "network": { "no_proxy": ["y"] } }), "", "drop-in");
+    assert_eq!(origins["network.http_proxy"], "file");
+    assert_eq!(origins["network.no_proxy"], "drop-in");
+    assert_eq!(origins["verbose"], "file");
+
+    // setting a whole object replaces the origins of everything inside it
+    set_origin(&mut origins, "network", "--set".to_string());
+    assert_eq!(origins.len(), 2);
+    assert_eq!(origins["network"], "--set");
+  }
+}
@@ -1,23 +1,451 @@
-use crate::package::Packages;
+use std::collections::{HashMap, HashSet};
+use crate::package::{compare_versions, is_valid_version, InstallReason, PackageFile, Packages};
+use crate::repo::{find_newest_package, find_provider, find_replacement, merge_repos, repos_for_target, split_repository, Repo};
+use crate::search::package_not_found;
 
 
-pub fn check_dependency(dependency: String, min_ver: String) -> bool {
-    // first see if the dependency is already installed
-    let mut packages = Packages::new();
-    packages.load().unwrap();
+/// A package that is part of an install or upgrade transaction, along with the mirror it will be downloaded from.
+pub struct PlannedPackage {
+    pub mirror: String,
+    pub package: PackageFile,
+    // installed packages this one replaces, which get removed once it is installed
+    pub replaces: Vec<String>,
+    // features to enable, carried over from the installed package
+    pub features: Vec<String>,
+}
 
-    if packages.has_package(dependency.clone()) {
-        // check to see if the version is greater than or equal to the minimum version
-        let package = packages.get_package(dependency).unwrap();
-        let version = package.version.clone();
+impl PlannedPackage {
+    // keeps only the features that the planned version still has
+    fn new(mirror: String, package: PackageFile, replaces: Vec<String>, mut features: Vec<String>) -> Self {
+        features.retain(|feature| package.features.contains_key(feature));
+        PlannedPackage { mirror, package, replaces, features }
+    }
+
+    /// The dependencies of the planned package, including those of its enabled features.
+    pub fn dependencies(&self) -> HashMap<String, String> {
+        self.package.dependencies_with_features(&self.features)
+    }
+}
+
+/// Finds every package in `targets` that has a newer version in any of the repos than the one installed.
+pub fn find_updates(installed: &Packages, repos: &[(String, Repo)], targets: Vec<String>) -> Vec<PlannedPackage> {
+    let mut updates = Vec::new();
+    for name in targets.iter() {
+        let installed_version = match installed.get_package(name.clone()) {
+            Some(package) => package.version.clone(),
+            None => {
+                println!("Package {} is not installed, skipping", name);
+                continue;
+            }
+        };
+        // a database written before versions were checked can hold ones that can't be compared
+        if !is_valid_version(&installed_version) {
+            println!("Package {} is installed as version \"{}\", which is not a major.minor.patch version, skipping", name, installed_version);
+            continue;
+        }
+        match find_newest_package(repos, name) {
+            Some((mirror, package)) => {
+                if compare_versions(package.version.clone(), installed_version) > 0 {
+                    let features = installed.get_features(name.clone());
+                    updates.push(PlannedPackage::new(mirror, package, Vec::new(), features));
+                }
+            }
+            None => println!("Package {} was not found in any mirror, skipping", name),
+        }
+    }
+    updates
+}
+
+// whether a dependency is met once the planned packages are installed and the packages they replace are removed
+fn dependency_satisfied(installed: &Packages, planned: &HashMap<String, PlannedPackage>, replaced: &HashSet<String>, dependency: &String, min_ver: &str) -> bool {
+    if let Some(planned_package) = planned.get(dependency) {
+        return compare_versions(planned_package.package.version.clone(), min_ver.to_string()) >= 0;
+    }
+    if !replaced.contains(dependency) {
+        if let Some(package) = installed.get_package(dependency.clone()) {
+            return compare_versions(package.version.clone(), min_ver.to_string()) >= 0;
+        }
+    }
+    // a virtual package is satisfied by anything that provides it, whatever the version
+    planned.values().any(|p| p.package.satisfies(dependency))
+        || installed.get_packages().iter().any(|p| !replaced.contains(&p.name) && p.satisfies(dependency))
+}
+
+/// Works out every package that needs to be installed to upgrade `targets` to the newest versions
+/// available in `repos`, including any dependencies that are missing or too old.
+/// Targets that a package in the repos now replaces are migrated to the replacement.
+/// The returned packages are in dependency order, so each one can be installed after the ones before it.
+pub fn plan_upgrade(installed: &Packages, repos: &[(String, Repo)], targets: Vec<String>) -> Result<Vec<PlannedPackage>, std::io::Error> {
+    // first find every target that has a newer version available
+    let mut planned: HashMap<String, PlannedPackage> = HashMap::new();
+    for planned_package in find_updates(installed, repos, targets.clone()) {
+        planned.insert(planned_package.package.name.clone(), planned_package);
+    }
+
+    // migrate any targets that have been renamed
+    for name in targets.iter() {
+        if installed.get_package(name.clone()).is_none() {
+            continue;
+        }
+        if let Some((mirror, package)) = find_replacement(repos, name) {
+            if installed.get_package(package.name.clone()).is_some() {
+                continue;
+            }
+            planned.remove(name);
+            let features = installed.get_features(name.clone());
+            planned.entry(package.name.clone())
+                .or_insert(PlannedPackage::new(mirror, package, Vec::new(), features))
+                .replaces.push(name.clone());
+        }
+    }
+
+    complete_plan(installed, repos, planned, HashMap::new())
+}
+
+/// Works out everything needed to install `targets` (each `package` or `repo/package`) with the given features enabled.
+/// Each target is resolved to the package that provides it, and an unknown target or feature is an error.
+/// Returns the packages to install in dependency order, along with the names the targets resolved to.
+/// Targets that are installed and up to date are left out, but the optional dependencies of any
+/// features newly enabled on them are still planned.
+pub fn plan_install(installed: &Packages, repos: &[(String, Repo)], targets: Vec<String>, features: Vec<String>) -> Result<(Vec<PlannedPackage>, Vec<String>), std::io::Error> {
+    let mut planned: HashMap<String, PlannedPackage> = HashMap::new();
+    let mut required: HashMap<String, String> = HashMap::new();
+    let mut resolved = Vec::new();
+    for target in targets.iter() {
+        let target_repos = repos_for_target(repos, target)?;
+        let name = split_repository(target).1;
+        let (mirror, package) = match find_provider(&target_repos, name) {
+            Some(found) => found,
+            None => {
+                let candidates: Vec<PackageFile> = merge_repos(&target_repos).into_iter().map(|(_, p)| p).collect();
+                return Err(package_not_found(name, &candidates, "was not found in any mirror"));
+            }
+        };
+        let unknown_features: Vec<String> = features.iter().filter(|f| !package.features.contains_key(*f)).cloned().collect();
+        if !unknown_features.is_empty() {
+            return Err(std::io::Error::other(format!("Package {} has no features named {}", package.name, unknown_features.join(", "))));
+        }
+        let mut enabled = installed.get_features(package.name.clone());
+        for feature in features.iter() {
+            if !enabled.contains(feature) {
+                enabled.push(feature.clone());
+            }
+        }
+        if !resolved.contains(&package.name) {
+            resolved.push(package.name.clone());
+        }
+
+        let up_to_date = match installed.get_package(package.name.clone()) {
+            Some(current) => compare_versions(current.version.clone(), package.version.clone()) >= 0,
+            None => false,
+        };
+        if up_to_date {
+            required.extend(package.dependencies_with_features(&enabled));
+        } else {
+            let replaces: Vec<String> = package.replaces.iter()
+                .filter(|r| installed.get_package(r.to_string()).is_some())
+                .cloned()
+                .collect();
+            planned.insert(package.name.clone(), PlannedPackage::new(mirror, package, replaces, enabled));
+        }
+    }
+
+    Ok((complete_plan(installed, repos, planned, required)?, resolved))
+}
+
+/// Adds the dependencies of the `planned` packages, plus any extra `required` dependencies, that aren't already satisfied.
+/// Then checks the result for conflicts and puts it into dependency order.
+fn complete_plan(installed: &Packages, repos: &[(String, Repo)], mut planned: HashMap<String, PlannedPackage>, required: HashMap<String, String>) -> Result<Vec<PlannedPackage>, std::io::Error> {
+    let mut replaced: HashSet<String> = planned.values().flat_map(|p| p.replaces.iter().cloned()).collect();
+
+    // pull in any dependencies that aren't already satisfied, along with their own dependencies
+    let mut pending: Vec<(String, HashMap<String, String>)> = planned.iter()
+        .map(|(name, p)| (name.clone(), p.dependencies()))
+        .collect();
+    pending.push(("the requested features".to_string(), required));
+    while let Some((name, dependencies)) = pending.pop() {
+        for (dependency, min_ver) in dependencies {
+            if dependency_satisfied(installed, &planned, &replaced, &dependency, &min_ver) {
+                continue;
+            }
+
+            let (mirror, package) = find_provider(repos, &dependency).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, format!("Dependency {} of {} was not found in any mirror", dependency, name))
+            })?;
+            if package.name == dependency && compare_versions(package.version.clone(), min_ver.clone()) < 0 {
+                return Err(std::io::Error::other(format!("{} needs {} {} but only {} is available", name, dependency, min_ver, package.version)));
+            }
+            // a new dependency might itself replace something that is installed
+            let replaces: Vec<String> = package.replaces.iter()
+                .filter(|r| installed.get_package(r.to_string()).is_some())
+                .cloned()
+                .collect();
+            replaced.extend(replaces.iter().cloned());
+            // a dependency that is already installed keeps its features, and needs what they need
+            let features = installed.get_features(package.name.clone());
+            let planned_package = PlannedPackage::new(mirror, package, replaces, features);
+            pending.push((planned_package.package.name.clone(), planned_package.dependencies()));
+            planned.insert(planned_package.package.name.clone(), planned_package);
+        }
+    }
+
+    // make sure nothing in the plan conflicts with what will be installed afterwards
+    let mut remaining: Vec<PackageFile> = installed.get_packages().into_iter()
+        .filter(|p| !replaced.contains(&p.name) && !planned.contains_key(&p.name))
+        .collect();
+    remaining.extend(planned.values().map(|p| p.package.clone()));
+    for planned_package in planned.values() {
+        for other in remaining.iter() {
+            if other.name != planned_package.package.name && planned_package.package.conflicts_with(other) {
+                return Err(std::io::Error::other(format!("{} conflicts with {}", planned_package.package.name, other.name)));
+            }
+        }
+    }
+
+    // finally put the packages into dependency order
+    let graph: HashMap<String, Vec<String>> = planned.iter()
+        .map(|(name, p)| {
+            let dependencies = p.dependencies().keys()
+                .filter_map(|dependency| {
+                    if planned.contains_key(dependency) {
+                        Some(dependency.clone())
+                    } else {
+                        planned.values().find(|other| other.package.satisfies(dependency)).map(|other| other.package.name.clone())
+                    }
+                })
+                .collect();
+            (name.clone(), dependencies)
+        })
+        .collect();
+    let order = dependency_order(&graph);
 
-        // check to see if the version is greater than or equal to the minimum version
-        if crate::package::compare_versions(version, min_ver) < 0 {
-            return false;
+    Ok(order.into_iter().map(|name| planned.remove(&name).unwrap()).collect())
+}
+
+/// Orders the packages in `graph` (package name -> names it depends on) so every package comes after its dependencies.
+/// Dependencies that aren't themselves in the graph are ignored.
+fn dependency_order(graph: &HashMap<String, Vec<String>>) -> Vec<String> {
+    let mut names: Vec<&String> = graph.keys().collect();
+    names.sort();
+    let mut order: Vec<String> = Vec::new();
+    let mut visited: HashSet<String> = HashSet::new();
+    for name in names {
+        visit_dependencies(name, graph, &mut visited, &mut order);
+    }
+    order
+}
+
+// depth first walk that adds a package to `order` only after all of its dependencies in the graph
+fn visit_dependencies(name: &String, graph: &HashMap<String, Vec<String>>, visited: &mut HashSet<String>, order: &mut Vec<String>) {
+    if !visited.insert(name.clone()) {
+        return;
+    }
+    let mut dependencies: Vec<&String> = graph.get(name).unwrap().iter().collect();
+    dependencies.sort();
+    for dependency in dependencies {
+        if graph.contains_key(dependency) {
+            visit_dependencies(dependency, graph, visited, order);
         }
-    } else {
-        return false
     }
+    order.push(name.clone());
+}
+
+/// Finds every installed package outside of `names` that depends on one of `names`
+/// and would be left without anything else installed to satisfy that dependency.
+pub fn find_dependents(installed: &Packages, names: &HashSet<String>) -> Vec<String> {
+    let (removing, remaining): (Vec<PackageFile>, Vec<PackageFile>) = installed.get_packages().into_iter()
+        .partition(|p| names.contains(&p.name));
+    let mut dependents: Vec<String> = remaining.iter()
+        .filter(|p| installed.dependencies_of(p).keys().any(|d| {
+            removing.iter().any(|r| r.satisfies(d)) && !remaining.iter().any(|r| r.satisfies(d))
+        }))
+        .map(|p| p.name.clone())
+        .collect();
+    dependents.sort();
+    dependents
+}
 
-    true
-}
\ No newline at end of file
+/// Works out which packages to remove, and in what order, to remove `targets`.
+/// If other installed packages depend on a target, removal is refused unless `cascade` is set,
+/// in which case those dependents (and anything depending on them) are removed first.
+pub fn plan_removal(installed: &Packages, targets: Vec<String>, cascade: bool) -> Result<Vec<String>, std::io::Error> {
+    let mut removing: HashSet<String> = HashSet::new();
+    for target in targets {
+        if installed.get_package(target.clone()).is_none() {
+            // only installed packages can be removed, so suggest from those
+            return Err(package_not_found(&target, &installed.get_packages(), "is not installed"));
+        }
+        removing.insert(target);
+    }
+
+    let dependents = find_dependents(installed, &removing);
+    if !dependents.is_empty() && !cascade {
+        return Err(std::io::Error::other(format!("Other packages depend on the packages being removed: {}. Use --cascade to remove them too", dependents.join(", "))));
+    }
+
+    // keep pulling in dependents of dependents until nothing else is affected
+    let mut dependents = dependents;
+    while !dependents.is_empty() {
+        removing.extend(dependents);
+        dependents = find_dependents(installed, &removing);
+    }
+
+    // remove dependents before the packages they depend on
+    let graph: HashMap<String, Vec<String>> = removing.iter()
+        .map(|name| (name.clone(), installed.dependencies_of(installed.get_package(name.clone()).unwrap()).into_keys().collect()))
+        .collect();
+    let mut order = dependency_order(&graph);
+    order.reverse();
+    Ok(order)
+}
+
+/// Finds every package that was only installed as a dependency and that no remaining package depends on.
+/// Removing one orphan can leave its own dependencies orphaned, so this keeps going until nothing new is found.
+pub fn find_orphans(installed: &Packages) -> Vec<String> {
+    let mut orphans: HashSet<String> = HashSet::new();
+    loop {
+        let remaining: Vec<PackageFile> = installed.get_packages().into_iter()
+            .filter(|p| !orphans.contains(&p.name))
+            .collect();
+        let required: HashSet<String> = remaining.iter()
+            .flat_map(|p| installed.dependencies_of(p).into_keys())
+            .collect();
+
+        let new_orphans: Vec<String> = remaining.iter()
+            .filter(|p| installed.get_install_reason(p.name.clone()) == InstallReason::Dependency)
+            .filter(|p| !required.iter().any(|r| p.satisfies(r)))
+            .map(|p| p.name.clone())
+            .collect();
+        if new_orphans.is_empty() {
+            break;
+        }
+        orphans.extend(new_orphans);
+    }
+
+    let mut orphans: Vec<String> = orphans.into_iter().collect();
+    orphans.sort();
+    orphans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(name: &str, version: &str, dependencies: &[(&str, &str)]) -> PackageFile {
+        PackageFile {
+            name: name.to_string(),
+            version: version.to_string(),
+            dependencies: dependencies.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect(),
+            ..PackageFile::default()
+        }
+    }
+
+    fn repos(packages: Vec<PackageFile>) -> Vec<(String, Repo)> {
+        let repo = Repo {
+            packages: packages.into_iter().map(|p| (p.name.clone(), p)).collect(),
+            name: "main".to_string(),
+            priority: 0,
+        };
+        vec![("http://mirror".to_string(), repo)]
+    }
+
+    fn installed(packages: Vec<(PackageFile, InstallReason)>) -> Packages {
+        let mut installed = Packages::new();
+        for (package, reason) in packages {
+            let name = package.name.clone();
+            installed.add_package(name.clone(), package);
+            installed.set_install_reason(name, reason);
+        }
+        installed
+    }
+
+    fn names(plan: &[PlannedPackage]) -> Vec<&str> {
+        plan.iter().map(|p| p.package.name.as_str()).collect()
+    }
+
+    #[test]
+    fn upgrade_installs_dependencies_first() {
+        let installed = installed(vec![
+            (package("app", "1.0.0", &[("lib", "1.0.0")]), InstallReason::Explicit),
+            (package("lib", "1.0.0", &[]), InstallReason::Dependency),
+        ]);
+        let repos = repos(vec![
+            package("app", "2.0.0", &[("lib", "2.0.0")]),
+            package("lib", "2.0.0", &[("base", "1.0.0")]),
+            package("base", "1.0.0", &[]),
+        ]);
+        let plan = plan_upgrade(&installed, &repos, vec!["app".to_string()]).unwrap();
+        assert_eq!(names(&plan), ["base", "lib", "app"]);
+    }
+
+    #[test]
+    fn upgrade_leaves_up_to_date_packages_out() {
+        let installed = installed(vec![(package("app", "1.0.0", &[]), InstallReason::Explicit)]);
+        let repos = repos(vec![package("app", "1.0.0", &[])]);
+        assert!(plan_upgrade(&installed, &repos, vec!["app".to_string()]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn upgrade_fails_on_missing_or_too_old_dependencies() {
+        let installed = installed(vec![(package("app", "1.0.0", &[]), InstallReason::Explicit)]);
+        let missing = repos(vec![package("app", "2.0.0", &[("lib", "1.0.0")])]);
+        assert!(plan_upgrade(&installed, &missing, vec!["app".to_string()]).is_err());
+        let too_old = repos(vec![package("app", "2.0.0", &[("lib", "2.0.0")]), package("lib", "1.0.0", &[])]);
+        assert!(plan_upgrade(&installed, &too_old, vec!["app".to_string()]).is_err());
+    }
+
+    #[test]
+    fn orphans_include_dependencies_left_behind_by_other_orphans() {
+        let installed = installed(vec![
+            (package("app", "1.0.0", &[("lib", "1.0.0")]), InstallReason::Explicit),
+            (package("lib", "1.0.0", &[]), InstallReason::Dependency),
+            (package("stale", "1.0.0", &[("base", "1.0.0")]), InstallReason::Dependency),
+            (package("base", "1.0.0", &[]), InstallReason::Dependency),
+        ]);
+        assert_eq!(find_orphans(&installed), ["base", "stale"]);
+    }
+
+    #[test]
+    fn removal_refuses_dependents_unless_cascading() {
+        let installed = installed(vec![
+            (package("app", "1.0.0", &[("lib", "1.0.0")]), InstallReason::Explicit),
+            (package("tool", "1.0.0", &[("app", "1.0.0")]), InstallReason::Explicit),
+            (package("lib", "1.0.0", &[]), InstallReason::Dependency),
+        ]);
+        assert!(plan_removal(&installed, vec!["lib".to_string()], false).is_err());
+        let order = plan_removal(&installed, vec!["lib".to_string()], true).unwrap();
+        assert_eq!(order, ["tool", "app", "lib"]);
+    }
+
+    #[test]
+    fn dependency_order_ignores_dependencies_outside_the_graph() {
+        let graph: HashMap<String, Vec<String>> = [
+            ("c", vec!["b", "outside"]),
+            ("b", vec!["a"]),
+            ("a", vec![]),
+        ].into_iter().map(|(n, d)| (n.to_string(), d.into_iter().map(|d| d.to_string()).collect())).collect();
+        assert_eq!(dependency_order(&graph), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn upgrade_migrates_renamed_packages() {
+        let installed = installed(vec![(package("old", "1.0.0", &[]), InstallReason::Explicit)]);
+        let mut new = package("new", "1.0.0", &[]);
+        new.replaces = vec!["old".to_string()];
+        let plan = plan_upgrade(&installed, &repos(vec![new]), vec!["old".to_string()]).unwrap();
+        assert_eq!(names(&plan), ["new"]);
+        assert_eq!(plan[0].replaces, ["old"]);
+    }
+
+    #[test]
+    fn install_resolves_targets_to_providers_and_refuses_unknown_ones() {
+        let installed = installed(vec![]);
+        let mut bash = package("bash", "5.0.0", &[("libc", "1.0.0")]);
+        bash.provides = vec!["sh".to_string()];
+        let repos = repos(vec![bash, package("libc", "1.0.0", &[])]);
+        let (plan, targets) = plan_install(&installed, &repos, vec!["sh".to_string()], Vec::new()).unwrap();
+        assert_eq!(names(&plan), ["libc", "bash"]);
+        assert_eq!(targets, ["bash"]);
+        assert!(plan_install(&installed, &repos, vec!["zsh".to_string()], Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
+use crate::config::MirrorAuth;
+use crate::package::PackageFile;
+use crate::session::{apply_auth, Session};
+
+// how many times a download is tried before giving up
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// A file to download, where to put it and the checksum it has to match.
+pub struct Download {
+    pub name: String,
+    pub url: String,
+    pub path: String,
+    pub checksum: String,
+}
+
+fn file_style() -> ProgressStyle {
+    ProgressStyle::with_template("{msg:20} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} eta {eta}")
+        .unwrap()
+        .progress_chars("=> ")
+}
+
+fn total_style() -> ProgressStyle {
+    ProgressStyle::with_template("{msg:20} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} eta {eta}")
+        .unwrap()
+        .progress_chars("## ")
+}
+
+/// Downloads every file, at most `parallelism` at a time, streaming each body straight to disk.
+/// Shows a progress bar per file and one for the total, and checks each file against its checksum.
+/// Files are downloaded to `<path>.part`, which is resumed if it is left over from an earlier attempt,
+/// and only renamed to `path` once the checksum matches. A file already at `path` is used as is
+/// if it matches the checksum, and quarantined and downloaded again if it doesn't.
+pub async fn download_files(session: &Session, downloads: Vec<Download>, parallelism: usize) -> Result<(), std::io::Error> {
+    if downloads.is_empty() {
+        return Ok(());
+    }
+
+    let progress = MultiProgress::new();
+    let total = progress.add(ProgressBar::new(0));
+    total.set_style(total_style());
+    total.set_message(format!("total ({} files)", downloads.len()));
+
+    let semaphore = Arc::new(Semaphore::new(parallelism.max(1)));
+    let mut handles = Vec::new();
+    for download in downloads {
+        let semaphore = semaphore.clone();
+        let progress = progress.clone();
+        let total = total.clone();
+        let client = session.client().clone();
+        let auth = session.auth_for(&download.url).cloned();
+        handles.push(tokio::spawn(async move {
+            let _permit = semaphore.acquire_owned().await.unwrap();
+            download_file(&client, auth.as_ref(), &download, &progress, &total).await
+        }));
+    }
+
+    // wait for everything to finish even if something fails, so no download is left writing in the background
+    let mut first_error = None;
+    for handle in handles {
+        let result = handle.await.unwrap_or_else(|e| Err(std::io::Error::other(e)));
+        if let Err(e) = result {
+            first_error.get_or_insert(e);
+        }
+    }
+    total.finish();
+
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+// where a download is kept until it is complete and its checksum has been verified
+fn part_path(download: &Download) -> String {
+    download.path.clone() + ".part"
+}
+
+/// Moves a file that failed verification into a `quarantine` directory next to it,
+/// so it is never used again but is still around to inspect. Returns where it was moved to.
+pub fn quarantine_file(path: &str) -> Result<String, std::io::Error> {
+    let path = Path::new(path);
+    let directory = path.parent().unwrap_or(Path::new(".")).join("quarantine");
+    std::fs::create_dir_all(&directory)?;
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let destination = directory.join(format!("{}.{}", file_name, timestamp));
+    std::fs::rename(path, &destination)?;
+    Ok(destination.to_string_lossy().to_string())
+}
+
+async fn download_file(client: &reqwest::Client, auth: Option<&MirrorAuth>, download: &Download, progress: &MultiProgress, total: &ProgressBar) -> Result<(), std::io::Error> {
+    let bar = progress.insert_before(total, ProgressBar::new(0));
+    bar.set_style(file_style());
+    bar.set_message(download.name.clone());
+
+    // an archive already in storage could be truncated or tampered with, so it is only used if it still matches the index
+    if Path::new(&download.path).exists() {
+        if PackageFile::check_hash(download.path.clone(), download.checksum.clone()) {
+            bar.finish_with_message(format!("{} (cached)", download.name));
+            return Ok(());
+        }
+        let quarantined = quarantine_file(&download.path)?;
+        progress.suspend(|| println!("Cached {} does not match the index, moved it to {}", download.name, quarantined));
+    }
+
+    // a part file left over from an earlier run may be of an older version of the archive
+    let part = part_path(download);
+    let leftover = tokio::fs::metadata(&part).await.is_ok_and(|metadata| metadata.len() > 0);
+    fetch_with_retries(client, auth, download, progress, &bar, total).await?;
+
+    // verify package integrity before the file goes anywhere near the storage directory
+    let mut verified = PackageFile::check_hash(part.clone(), download.checksum.clone());
+    if !verified && leftover {
+        progress.suspend(|| println!("The partial download of {} does not match the index, downloading it again from the start", download.name));
+        tokio::fs::remove_file(&part).await?;
+        fetch_with_retries(client, auth, download, progress, &bar, total).await?;
+        verified = PackageFile::check_hash(part.clone(), download.checksum.clone());
+    }
+    if !verified {
+        bar.abandon();
+        // the part file is bad, so throw it away rather than resuming from it next time
+        tokio::fs::remove_file(&part).await?;
+        return Err(std::io::Error::other(format!("Package hash does not match for {}", download.name)));
+    }
+    bar.finish();
+    tokio::fs::rename(&part, &download.path).await?;
+
+    Ok(())
+}
+
+// fetches the part file, retrying a few times when the connection drops and picking up where it left off
+async fn fetch_with_retries(client: &reqwest::Client, auth: Option<&MirrorAuth>, download: &Download, progress: &MultiProgress, bar: &ProgressBar, total: &ProgressBar) -> Result<(), std::io::Error> {
+    let mut attempt = 1;
+    loop {
+        match fetch_part(client, auth, download, bar, total).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < DOWNLOAD_ATTEMPTS && e.kind() != std::io::ErrorKind::NotFound => {
+                attempt += 1;
+                progress.suspend(|| println!("Download of {} failed ({}), resuming", download.name, e));
+            }
+            Err(e) => {
+                bar.abandon();
+                return Err(e);
+            }
+        }
+    }
+}
+
+// downloads whatever is missing from the part file, resuming with a range request if part of it is already there
+async fn fetch_part(client: &reqwest::Client, auth: Option<&MirrorAuth>, download: &Download, bar: &ProgressBar, total: &ProgressBar) -> Result<(), std::io::Error> {
+    let part = part_path(download);
+    let existing = match tokio::fs::metadata(&part).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+
+    let mut request = apply_auth(client.get(download.url.as_str()), auth);
+    if existing > 0 {
+        request = request.header(RANGE, format!("bytes={}-", existing));
+    }
+    let mut response = request.send().await.map_err(std::io::Error::other)?;
+
+    let mut file = if response.status() == StatusCode::PARTIAL_CONTENT {
+        OpenOptions::new().append(true).open(&part).await?
+    } else if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // the part file already has everything, or is longer than the file on the mirror
+        // either way the checksum decides whether it can be used
+        return Ok(());
+    } else if response.status().is_success() {
+        // the mirror doesn't support ranges (or nothing was downloaded yet), so start from scratch
+        tokio::fs::File::create(&part).await?
+    } else {
+        // only a file that isn't on the mirror is given up on straight away, anything else may be temporary
+        let kind = match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::GONE => std::io::ErrorKind::NotFound,
+            _ => std::io::ErrorKind::Other,
+        };
+        return Err(std::io::Error::new(kind, format!("Could not download {}: {}", download.url, response.status())));
+    };
+    let resumed = if response.status() == StatusCode::PARTIAL_CONTENT { existing } else { 0 };
+
+    // the first attempt adds this file to the total, later attempts rewind it to where they pick up
+    let remaining = response.content_length().unwrap_or(0);
+    if bar.length() == Some(0) {
+        total.inc_length(resumed + remaining);
+    }
+    total.set_position(total.position() - bar.position() + resumed);
+    bar.set_length(resumed + remaining);
+    bar.set_position(resumed);
+
+    while let Some(chunk) = response.chunk().await.map_err(std::io::Error::other)? {
+        file.write_all(&chunk).await?;
+        bar.inc(chunk.len() as u64);
+        total.inc(chunk.len() as u64);
+    }
+    file.flush().await?;
+
+    Ok(())
+}
@@ -1,234 +1,415 @@
-use std::fs::File;
-use std::io::{Write};
+use std::path::Path;
 use std::process::Command;
 use crate::config::Config;
-use crate::dephandle::check_dependency;
-use crate::package;
-use crate::package::{PackageFile, Packages};
-use crate::repo::{check_repo_for_package, download_repo_file, Repo};
+use crate::dephandle::{find_orphans, plan_install, plan_removal, plan_upgrade, PlannedPackage};
+use crate::download::{download_files, quarantine_file, Download};
+use crate::journal::{Journal, Operation, Step};
+use crate::script::{run_script, Script};
+use crate::package::{InstallReason, PackageFile, Packages};
+use crate::repo::{repository_name, Repo};
+use crate::session::Session;
 
 // TODO: Read: https://doc.rust-lang.org/rust-by-example/error/multiple_error_types.html
 // see how to process multiple errors
 
-async fn get_package_from_mirror(url: String) -> Result<Vec<u8>, reqwest::Error> {
-    let response = reqwest::get(url.as_str()).await?;
-    let package_file = response.bytes().await?.to_vec();
-    Ok(package_file)
+/// Builds the download of `<package>.uspm` from the mirror into the storage directory.
+fn package_download(config: &Config, mirror: &str, package: &str, checksum: String) -> Download {
+    Download {
+        name: package.to_string(),
+        url: mirror.to_string() + "/" + package + ".uspm",
+        path: config.storage_location().to_string() + "/" + package + ".uspm",
+        checksum,
+    }
 }
 
-#[tokio::main]
-pub async fn download_package(package: String) -> Result<bool, std::io::Error> {
-    let config: Config;
-    let mut url: String = "".to_string();
-    let config_result = Config::load();
+/// Downloads the archives of every planned package, several at a time.
+async fn download_planned_packages(session: &Session, plan: &[PlannedPackage]) -> Result<(), std::io::Error> {
+    let config = session.config();
+    let downloads = plan.iter()
+        .map(|planned| package_download(config, &planned.mirror, &planned.package.name, planned.package.checksum.clone()))
+        .collect();
+    download_files(session, downloads, config.parallel_downloads()).await
+}
 
-    // If the config file doesn't exist, create it and use the default config.
-    if config_result.is_err() {
-        config = Config::default();
-        config.save().expect("Could not save default config")
-    } else {
-        config = config_result.unwrap();
-    }
-
-    // attempt to download the package from the first mirror in the config file
-    // that has the package
-    for mirror in config.mirrors() {
-        let repo_result = download_repo_file(mirror).await;
-        if repo_result.is_err() {
-            continue;
-        } else {
-            let repo = repo_result.unwrap().clone();
-            if check_repo_for_package(repo, &package) {
-                url = mirror.to_string();
-            }
-        }
-    }
+/// Extracts `<package>.uspm` in the storage directory and loads the package.json inside it.
+fn extract_package(config: &Config, package: &str) -> Result<PackageFile, std::io::Error> {
+    let file_path = config.storage_location().to_string() + "/" + package + ".uspm";
 
-    // if the package was not found in any mirror, return false
-    if url.is_empty() {
-        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Package not found"));
+    // extract package file with tar
+    let output = Command::new("tar")
+        .arg("-xvf")
+        .arg(&file_path)
+        .current_dir(config.storage_location())
+        .output()
+        .map_err(|e| std::io::Error::new(e.kind(), format!("Could not run tar to extract {}: {}", file_path, e)))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(std::io::Error::other(format!("Could not extract {}: {}", file_path, stderr.trim())));
     }
 
-    // download package to storage directory
-    // add package file to url
-
-    let file_url = url.clone() + "/" + &package + ".uspm";
-
-    let file_path: String = config.storage_location().to_string() + "/" + &package + ".uspm";
-    let mut file = File::create(file_path.clone())?;
-    let package_result = get_package_from_mirror(file_url).await;
-    if package_result.is_err() {
-        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Package not found"));
+    // check package.json for dependencies by going to the package directory
+    // and reading the package.json file
+    let package_json = config.storage_location().to_string() + "/" + package + "/package.json";
+    let p_file = PackageFile::load(package_json.clone())
+        .map_err(|e| std::io::Error::new(e.kind(), format!("Could not load {}: {}", package_json, e)))?;
+
+    // versions are compared all over the place, so one that can't be is never recorded
+    if let Some(version) = p_file.invalid_version() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+            format!("Package {} has an invalid package.json: \"{}\" is not a major.minor.patch version", package, version)));
+    }
+    if !p_file.check() {
+        return Err(std::io::Error::other(format!("Package {} has an invalid package.json", package)));
     }
 
-    let package_result_u8 = package_result.unwrap();
+    Ok(p_file)
+}
 
-    // convert vector to &[u8]
-    let package_slice = &package_result_u8[..];
+/// The installed packages that `replacement` replaces.
+fn replaced_packages(packages: &Packages, replacement: &PackageFile) -> Vec<String> {
+    replacement.replaces.iter()
+        .filter(|name| packages.get_package(name.to_string()).is_some())
+        .cloned()
+        .collect()
+}
 
-    file.write_all(package_slice)?;
+/// Records `package` as installed in place of the `replaced` packages.
+/// If any of the replaced packages were installed explicitly, so is the replacement.
+fn record_replacement(packages: &mut Packages, package: &str, replaced: Vec<String>) {
+    for name in replaced {
+        if packages.get_install_reason(name.clone()) == InstallReason::Explicit {
+            packages.set_install_reason(package.to_string(), InstallReason::Explicit);
+        }
+        packages.remove_package(name);
+    }
+}
 
-    // verify package integrity
+/// Installs an extracted package whose dependencies are in place, from the step its journal is on.
+/// Each step is written to the journal before it starts, and the journal is finished once the
+/// package is recorded in the database.
+fn run_install_steps(config: &Config, packages: &mut Packages, p_file: PackageFile, journal: &mut Journal) -> Result<(), std::io::Error> {
+    let package = journal.package.clone();
+    let versions = (journal.previous_version.clone(), Some(journal.version.clone()));
+    let (pre_hook, post_hook) = journal.hooks();
+    if journal.step == Step::PreHook {
+        run_script(config, &package, pre_hook, versions.0.as_deref(), versions.1.as_deref())?;
+        journal.start(Step::RemoveReplaced)?;
+    }
+    if journal.step == Step::RemoveReplaced {
+        // this happens before the replacement's install script so the old packages can't remove its files
+        for name in journal.replaced.iter() {
+            println!("{} replaces {}, removing {}", package, name, name);
+            let version = packages.get_package(name.clone()).map(|p| p.version.clone());
+            run_removal_scripts(config, name, version.as_deref())?;
+        }
+        journal.start(Step::InstallScript)?;
+    }
+    for (step, script, next) in [
+        (Step::InstallScript, Script::Install, Step::PostHook),
+        (Step::PostHook, post_hook, Step::Record),
+    ] {
+        if journal.step == step {
+            run_script(config, &package, script, versions.0.as_deref(), versions.1.as_deref())?;
+            journal.start(next)?;
+        }
+    }
 
-    // get the repo file from the mirror
-    let repo_result = download_repo_file(&url).await;
-    if repo_result.is_err() {
-        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Package not found"));
+    if packages.has_package(package.clone()) {
+        // upgrading a package only ever promotes it to explicit, never demotes it to a dependency
+        if journal.reason == InstallReason::Explicit {
+            packages.set_install_reason(package.clone(), journal.reason);
+        }
+        packages.replace_package(package.clone(), p_file);
+    } else {
+        packages.add_package(package.clone(), p_file);
+        packages.set_install_reason(package.clone(), journal.reason);
     }
+    packages.set_features(package.clone(), journal.features.clone());
+    packages.set_origin(&package, journal.repository.clone(), journal.digest.clone());
+    record_replacement(packages, &package, journal.replaced.clone());
+    packages.save()?;
 
-    let repo = repo_result.unwrap();
-    
-    // get the checksum from the repo file
-    let package_file = repo.get_package(package.clone()).unwrap();
-    let checksum = package_file.checksum.clone();
+    journal.finish()
+}
 
-    // check hash
-    let hash_result = PackageFile::check_hash(file_path, checksum);
+// turns the error a journaled operation failed with into what to tell the user. a failed pre hook
+// hasn't changed anything so the operation is simply dropped, anything later has to be resumed or rolled back
+fn abort_operation(journal: &Journal, error: std::io::Error) -> std::io::Error {
+    if journal.step == Step::PreHook {
+        return match journal.finish() {
+            Ok(()) => error,
+            Err(e) => e,
+        };
+    }
+    if !journal.can_roll_back() {
+        return std::io::Error::new(error.kind(), format!("{}. Run `uspm-rust recover resume` once that is fixed", error));
+    }
+    std::io::Error::new(error.kind(), format!("{}. Run `uspm-rust recover resume` once that is fixed or `uspm-rust recover rollback` to undo it", error))
+}
 
-    if !hash_result {
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "Package hash does not match"));
+/// Reads the installed package database for a command that only looks at it, without ever writing it.
+/// There being no database yet just means nothing is installed.
+pub fn read_packages() -> Result<Packages, std::io::Error> {
+    let mut packages = Packages::new();
+    match packages.read() {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(packages),
+        result => result.map(|_| packages),
     }
+}
 
-    Ok(true)
+/// Loads the installed package database, starting a new one if there isn't one yet.
+/// A database that exists but can't be loaded (or restored from a backup) is an error, never replaced.
+/// This can write the database, so only commands holding the package database lock use it.
+pub fn load_packages() -> Result<Packages, std::io::Error> {
+    let mut packages = Packages::new();
+    match packages.load() {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("No packages file yet, making a new one");
+            packages.save()?;
+        }
+        result => result?,
+    }
+    Ok(packages)
 }
 
-pub fn install_package(package: String) -> Result<bool, std::io::Error> {
-    let config: Config;
-    let config_result = Config::load();
+/// Installs the given packages explicitly with the given features enabled, along with any of their
+/// dependencies that aren't already satisfied. Everything the install will need is planned and
+/// downloaded up front, several archives at a time, and then installed in dependency order.
+pub async fn install_packages(session: &Session, targets: Vec<String>, features: Vec<String>) -> Result<(), std::io::Error> {
+    let config = session.config();
+    let mut packages = load_packages()?;
+
+    let repos = session.repos().await;
+    let (plan, targets) = plan_install(&packages, &repos, targets, features.clone())?;
+    download_planned_packages(session, &plan).await?;
+
+    let planned_names: Vec<String> = plan.iter().map(|planned| planned.package.name.clone()).collect();
+    for planned in plan {
+        let reason = if targets.contains(&planned.package.name) { InstallReason::Explicit } else { InstallReason::Dependency };
+        install_planned(config, &mut packages, &repos, planned, reason)?;
+    }
 
-    // If the config file doesn't exist, create it and use the default config.
-    if config_result.is_err() {
-        config = Config::default();
-        config.save().expect("Could not save default config")
-    } else {
-        config = config_result.unwrap();
-    }
-
-    // first see if the package file exists in the storage directory
-    let file_path = config.storage_location().to_string() + "/" + &package + ".uspm";
-    // if it doesn't, download it
-    if !std::path::Path::new(&file_path).exists() {
-        let download_result = download_package(package.clone());
-        if download_result.is_err() {
-            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Package not found"));
-        } else {
-            let download_result = download_result.unwrap();
-            if !download_result {
-                return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Package not found"));
+    // targets that were already up to date only get the features they were asked for with
+    for package in targets.into_iter().filter(|target| !planned_names.contains(target)) {
+        println!("Package {} is already installed and is up to date!", package);
+        let mut enabled_features = packages.get_features(package.clone());
+        for feature in features.iter() {
+            if !enabled_features.contains(feature) {
+                enabled_features.push(feature.clone());
             }
         }
+        packages.set_features(package.clone(), enabled_features);
+        // explicitly installing a package that was pulled in as a dependency keeps it around for good
+        packages.set_install_reason(package, InstallReason::Explicit);
+        packages.save()?;
     }
+    Ok(())
+}
 
-    // now we need to extract the package file and check its package.json file
-    // to see if it has any dependencies
-    // if it does, we need to install those dependencies
-    // then we need to run the install script
+// extracts a planned package that has been downloaded and installs it with the given reason.
+// every package is saved as it is done, so a failure part way through a plan still records what was installed
+fn install_planned(config: &Config, packages: &mut Packages, repos: &[(String, Repo)], planned: PlannedPackage, reason: InstallReason) -> Result<(), std::io::Error> {
+    let name = planned.package.name.clone();
+    println!("Installing {} {}", name, planned.package.version);
+    let p_file = extract_package(config, &name)?;
+    let replaced = replaced_packages(packages, &p_file);
+
+    let repository = repository_name(repos, &planned.mirror);
+    let previous_version = packages.get_package(name.clone()).map(|p| p.version.clone());
+    let mut journal = Journal::install(&planned.package, previous_version, repository, reason, planned.features.clone(), replaced)?;
+    run_install_steps(config, packages, p_file, &mut journal).map_err(|e| abort_operation(&journal, e))
+}
 
-    // extract package file with tar
-    Command::new("tar")
-        .arg("-xvf")
-        .arg(&file_path)
-        .current_dir(&config.storage_location())
-        .output()
-        .expect("failed to execute process");
+/// Upgrades the given packages, or every installed package if `targets` is empty.
+/// All of the upgrades and any new dependencies they need are planned up front as one transaction
+/// and then installed in dependency order.
+pub async fn upgrade_packages(session: &Session, targets: Vec<String>) -> Result<(), std::io::Error> {
+    let config = session.config();
+    let mut packages = load_packages()?;
+
+    let targets = if targets.is_empty() {
+        let mut names: Vec<String> = packages.get_packages().into_iter().map(|p| p.name).collect();
+        names.sort();
+        names
+    } else {
+        targets
+    };
 
-    // check package.json for dependencies by going to the package directory
-    // and reading the package.json file
-    let package_json = config.storage_location().to_string() + "/" + &package + "/package.json";
-    let package_load = PackageFile::load(package_json);
+    let repos = session.repos().await;
+    let plan = plan_upgrade(&packages, &repos, targets)?;
 
-    if package_load.is_err() {
-        // crash
-        panic!("Package issue! Please delete offending files and try again.")
+    if plan.is_empty() {
+        println!("All packages are up to date!");
+        return Ok(());
     }
 
-    let p_file = package_load.unwrap();
-    let mut packages = Packages::new();
-    if packages.load().is_err() {
-        println!("Packages file could not be loaded! Making new packages file");
-        packages.save().expect("Could not save packages file, exiting");
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "Packages file could not be saved!"))
-    } else {
-        packages.load().unwrap();
+    println!("The following packages will be upgraded or installed:");
+    for planned in plan.iter() {
+        let installed_version = packages.get_package(planned.package.name.clone()).map(|p| p.version.clone());
+        match installed_version {
+            Some(version) => println!("  {} {} -> {}", planned.package.name, version, planned.package.version),
+            None => println!("  {} {} (new)", planned.package.name, planned.package.version),
+        }
+        for replaced in planned.replaces.iter() {
+            println!("    replaces {}", replaced);
+        }
     }
 
-    // see if the package is already installed and if it is, check to see if the version is greater than or equal to the minimum version
-    // if it is, then we don't need to install it
-    // if it isn't, then we need to install it
-    if packages.has_package(package.clone()) {
-        let dled_package_version = p_file.version.clone();
-        let installed_package_version = packages.get_package(package.clone()).unwrap().version.clone();
-        if package::compare_versions(installed_package_version, dled_package_version) >= 0 {
-            println!("Package {} is already installed and is up to date!", package.clone());
-            return Ok(true);
-        }
+    download_planned_packages(session, &plan).await?;
+
+    // anything new in an upgrade is only there to satisfy a dependency, and upgrades keep the reason
+    // of what is already installed
+    for planned in plan {
+        install_planned(config, &mut packages, &repos, planned, InstallReason::Dependency)?;
     }
 
-    // install dependencies
-    for dependency in p_file.clone().dependencies {
-        // check dependency
-        if check_dependency(dependency.0.clone(), dependency.1) {
-            continue;
-        }
+    Ok(())
+}
 
-        // install dependency
-        let install_result = install_package(dependency.0.clone());
+// runs all of a package's removal scripts, for a package that is removed as part of another operation
+fn run_removal_scripts(config: &Config, package: &str, version: Option<&str>) -> Result<(), std::io::Error> {
+    for script in [Script::PreRemove, Script::Uninstall, Script::PostRemove] {
+        run_script(config, package, script, version, None)?;
+    }
+    Ok(())
+}
 
-        if install_result.is_err() {
-            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Package not found"));
+/// Removes an installed package, journaling each step.
+fn remove_package(config: &Config, packages: &mut Packages, package: &str) -> Result<(), std::io::Error> {
+    let version = packages.get_package(package.to_string()).map(|p| p.version.clone()).unwrap_or_default();
+    let mut journal = Journal::remove(package, &version, packages.get_install_reason(package.to_string()))?;
+    run_remove_steps(config, packages, &mut journal).map_err(|e| abort_operation(&journal, e))
+}
+
+// removes an installed package from the step its journal is on
+fn run_remove_steps(config: &Config, packages: &mut Packages, journal: &mut Journal) -> Result<(), std::io::Error> {
+    let (pre_hook, post_hook) = journal.hooks();
+    for (step, script, next) in [
+        (Step::PreHook, pre_hook, Step::UninstallScript),
+        (Step::UninstallScript, Script::Uninstall, Step::PostHook),
+        (Step::PostHook, post_hook, Step::Record),
+    ] {
+        if journal.step == step {
+            run_script(config, &journal.package, script, Some(&journal.version), None)?;
+            journal.start(next)?;
         }
     }
+    packages.remove_package(journal.package.clone());
+    packages.save()?;
+    journal.finish()
+}
 
-    // run install script
-    let install_script = config.storage_location().to_string() + "/" + &package + "/install.sh";
-    let install_command = Command::new("sh")
-        .arg(&install_script)
-        .current_dir(&config.storage_location())
-        .output();
-    if install_command.is_err() {
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "Install script failed!"));
+// makes sure the archive in storage is still the one the interrupted install started with before it is
+// extracted again, and quarantines it if it isn't, the same as a download that fails verification
+fn verify_stored_archive(config: &Config, journal: &Journal) -> Result<(), std::io::Error> {
+    let path = config.storage_location().to_string() + "/" + &journal.package + ".uspm";
+    let Some(digest) = journal.digest.clone() else {
+        return Err(std::io::Error::other(format!("The digest of {} was not recorded, so it can't be verified before resuming", path)));
+    };
+    if !Path::new(&path).exists() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} is no longer in storage", path)));
     }
-
-    if packages.has_package(package.clone()) {
-        packages.replace_package(package.clone(), p_file);
-    } else {
-        packages.add_package(package.clone(), p_file);
+    if !PackageFile::check_hash(path.clone(), digest) {
+        let quarantined = quarantine_file(&path)?;
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+            format!("{} no longer matches the archive the install started with, moved it to {}", path, quarantined)));
     }
+    Ok(())
+}
 
-    packages.save().unwrap();
+/// Finishes (`resume`) or undoes the operation an earlier uspm was part way through when it was interrupted.
+/// Rolling back an install runs the new package's uninstall.sh and reinstalls whatever it replaced.
+/// An upgrade can't be rolled back once its install.sh has started, see Journal::can_roll_back.
+/// Rolling back a removal runs the package's install.sh again.
+pub fn recover(config: &Config, resume: bool) -> Result<(), std::io::Error> {
+    let Some(mut journal) = Journal::load()? else {
+        println!("No interrupted operation to recover");
+        return Ok(());
+    };
+    let mut packages = load_packages()?;
+    // once the database has been saved there is nothing left to do either way
+    let recorded = journal.step == Step::Record && match journal.operation {
+        Operation::Install => packages.get_package(journal.package.clone()).is_some_and(|p| p.version == journal.version),
+        Operation::Remove => !packages.has_package(journal.package.clone()),
+    };
+    if recorded {
+        println!("{} had already finished", journal);
+        return journal.finish();
+    }
 
-    Ok(true)
+    match (journal.operation, resume) {
+        (Operation::Install, true) => {
+            println!("Resuming {}", journal);
+            verify_stored_archive(config, &journal)?;
+            let p_file = extract_package(config, &journal.package)?;
+            run_install_steps(config, &mut packages, p_file, &mut journal).map_err(|e| abort_operation(&journal, e))?;
+        }
+        (Operation::Install, false) => {
+            if !journal.can_roll_back() {
+                return Err(std::io::Error::other(format!(
+                    "{} can't be rolled back, the files of {} may already have been replaced. Run `uspm-rust recover resume` to finish it",
+                    journal, journal.previous_version.as_deref().unwrap_or_default())));
+            }
+            println!("Rolling back {}", journal);
+            // install.sh only has to be undone if it got as far as running, which it can't have for an upgrade
+            if matches!(journal.step, Step::InstallScript | Step::PostHook | Step::Record) {
+                run_script(config, &journal.package, Script::Uninstall, Some(&journal.version), None)?;
+            }
+            // the replaced packages are put back at the version they were at, which scripts see as a reinstall
+            for name in journal.replaced.iter().filter(|_| journal.step != Step::PreHook) {
+                println!("Reinstalling {}", name);
+                let version = packages.get_package(name.clone()).map(|p| p.version.clone()).unwrap_or_default();
+                run_script(config, name, Script::Install, Some(&version), Some(&version))?;
+            }
+            packages.save()?;
+            journal.finish()?;
+        }
+        (Operation::Remove, true) => {
+            println!("Resuming {}", journal);
+            run_remove_steps(config, &mut packages, &mut journal).map_err(|e| abort_operation(&journal, e))?;
+        }
+        (Operation::Remove, false) => {
+            println!("Rolling back {}", journal);
+            // nothing was removed yet if it stopped in pre_remove.sh. otherwise the same version is put back
+            if journal.step != Step::PreHook {
+                run_script(config, &journal.package, Script::Install, Some(&journal.version), Some(&journal.version))?;
+            }
+            journal.finish()?;
+        }
+    }
+    Ok(())
 }
 
-pub fn uninstall_package(package: String) -> Result<bool, std::io::Error>{
-    let config: Config;
-    let config_result = Config::load();
+/// Removes the given packages. Removal is refused if other installed packages depend on them,
+/// unless `cascade` is set, in which case the dependents are removed as well.
+pub fn uninstall_packages(config: &Config, targets: Vec<String>, cascade: bool) -> Result<(), std::io::Error> {
+    let mut packages = load_packages()?;
 
-    // If the config file doesn't exist, create it and use the default config.
-    if config_result.is_err() {
-        config = Config::default();
-        config.save().expect("Could not save default config")
-    } else {
-        config = config_result.unwrap();
+    let removal = plan_removal(&packages, targets.clone(), cascade)?;
+    for package in removal {
+        if !targets.contains(&package) {
+            println!("Removing dependent package {}", package);
+        }
+        remove_package(config, &mut packages, &package)?;
     }
 
-    let uninstall_script = config.storage_location().to_string() + "/" + &package + "/uninstall.sh";
-    Command::new("sh")
-        .arg(&uninstall_script)
-        .current_dir(&config.storage_location())
-        .output()
-        .expect("failed to execute process");
+    Ok(())
+}
 
-    let mut packages = Packages::new();
-    if packages.load().is_err() {
-        println!("Packages file could not be loaded! Making new packages file");
-        packages.save().expect("Could not save packages file, exiting");
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "Packages file could not be saved!"))
-    } else {
-        packages.load().unwrap();
+/// Removes every package that was installed as a dependency and is no longer needed by anything.
+pub fn autoremove_packages(config: &Config) -> Result<(), std::io::Error> {
+    let mut packages = load_packages()?;
+
+    let orphans = find_orphans(&packages);
+    if orphans.is_empty() {
+        println!("No packages to remove");
+        return Ok(());
     }
 
-    packages.remove_package(package.clone());
+    for orphan in orphans {
+        println!("Removing {}", orphan);
+        remove_package(config, &mut packages, &orphan)?;
+    }
 
-    Ok(true)
+    Ok(())
 }
@@ -0,0 +1,192 @@
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::atomic::write_atomic;
+use crate::package::{InstallReason, PackageFile};
+use crate::script::Script;
+
+/// Where the operation in progress is recorded. It only exists while a package is being installed
+/// or removed, so finding it means an earlier uspm was interrupted.
+pub const JOURNAL_PATH: &str = "/etc/uspm/journal.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Install,
+    Remove,
+}
+
+/// The steps of installing or removing a package, in the order they happen.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    // running pre_install.sh, pre_upgrade.sh or pre_remove.sh
+    PreHook,
+    // install: running the removal scripts of the packages it replaces
+    RemoveReplaced,
+    // install: running install.sh
+    InstallScript,
+    // remove: running uninstall.sh
+    UninstallScript,
+    // running post_install.sh, post_upgrade.sh or post_remove.sh
+    PostHook,
+    // saving the package database
+    Record,
+}
+
+/// A write-ahead record of the package operation in progress. Each step is written to it before
+/// the step starts, so an interrupted operation can be resumed or rolled back from where it stopped.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Journal {
+    pub operation: Operation,
+    pub package: String,
+    pub version: String,
+    // the version installed before an upgrade
+    #[serde(default)]
+    pub previous_version: Option<String>,
+    // the step that was started last, everything before it has finished
+    pub step: Step,
+    // what an install records once it is done
+    pub reason: InstallReason,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub replaced: Vec<String>,
+    // where the package being installed came from: its repository and the digest of its archive
+    #[serde(default)]
+    pub repository: Option<String>,
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+impl Journal {
+    /// Starts the journal of installing a package, upgrading `previous_version` if it is installed
+    /// and replacing the `replaced` packages. `indexed` is the package's entry in the index of the
+    /// `repository` it was downloaded from.
+    pub fn install(indexed: &PackageFile, previous_version: Option<String>, repository: Option<String>, reason: InstallReason, features: Vec<String>, replaced: Vec<String>) -> Result<Self, std::io::Error> {
+        let journal = Journal {
+            operation: Operation::Install,
+            package: indexed.name.clone(),
+            version: indexed.version.clone(),
+            previous_version,
+            step: Step::PreHook,
+            reason,
+            features,
+            replaced,
+            repository,
+            digest: Some(indexed.checksum.clone()),
+        };
+        journal.save()?;
+        Ok(journal)
+    }
+
+    /// Starts the journal of removing the installed `version` of `package`.
+    pub fn remove(package: &str, version: &str, reason: InstallReason) -> Result<Self, std::io::Error> {
+        let journal = Journal {
+            operation: Operation::Remove,
+            package: package.to_string(),
+            version: version.to_string(),
+            previous_version: None,
+            step: Step::PreHook,
+            reason,
+            features: Vec::new(),
+            replaced: Vec::new(),
+            repository: None,
+            digest: None,
+        };
+        journal.save()?;
+        Ok(journal)
+    }
+
+    /// The hooks run before and after the package's install.sh or uninstall.sh.
+    pub fn hooks(&self) -> (Script, Script) {
+        match self.operation {
+            Operation::Install => Script::install_hooks(self.previous_version.is_some()),
+            Operation::Remove => (Script::PreRemove, Script::PostRemove),
+        }
+    }
+
+    /// Whether the operation can still be undone. Once install.sh of an upgrade has started it may have
+    /// overwritten the previous version's files, and that version's archive is gone, so it can only be resumed.
+    pub fn can_roll_back(&self) -> bool {
+        let upgrade = self.operation == Operation::Install && self.previous_version.is_some();
+        !(upgrade && matches!(self.step, Step::InstallScript | Step::PostHook | Step::Record))
+    }
+
+    /// Records that `step` is about to start.
+    pub fn start(&mut self, step: Step) -> Result<(), std::io::Error> {
+        self.step = step;
+        self.save()
+    }
+
+    /// Marks the operation as finished.
+    pub fn finish(&self) -> Result<(), std::io::Error> {
+        std::fs::remove_file(JOURNAL_PATH)
+    }
+
+    fn save(&self) -> Result<(), std::io::Error> {
+        let serialized = serde_json::to_string_pretty(self)?;
+        write_atomic(JOURNAL_PATH, serialized.as_bytes())
+    }
+
+    /// The journal of an operation that was interrupted, if there is one.
+    pub fn load() -> Result<Option<Self>, std::io::Error> {
+        let contents = match std::fs::read_to_string(JOURNAL_PATH) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let journal = serde_json::from_str(&contents)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", JOURNAL_PATH, e)))?;
+        Ok(Some(journal))
+    }
+}
+
+impl fmt::Display for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operation = match self.operation {
+            Operation::Install => "installing",
+            Operation::Remove => "removing",
+        };
+        let step = match self.step {
+            Step::RemoveReplaced => format!("removing the packages it replaces ({})", self.replaced.join(", ")),
+            Step::PreHook => "running ".to_string() + self.hooks().0.file_name(),
+            Step::InstallScript => "running install.sh".to_string(),
+            Step::UninstallScript => "running uninstall.sh".to_string(),
+            Step::PostHook => "running ".to_string() + self.hooks().1.file_name(),
+            Step::Record => "saving the package database".to_string(),
+        };
+        write!(f, "{} {} {} (stopped while {})", operation, self.package, self.version, step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal(operation: Operation, previous_version: Option<&str>, step: Step) -> Journal {
+        Journal {
+            operation,
+            package: "app".to_string(),
+            version: "2.0.0".to_string(),
+            previous_version: previous_version.map(|v| v.to_string()),
+            step,
+            reason: InstallReason::Explicit,
+            features: Vec::new(),
+            replaced: Vec::new(),
+            repository: None,
+            digest: None,
+        }
+    }
+
+    #[test]
+    fn upgrades_can_only_be_rolled_back_before_install_sh() {
+        for step in [Step::PreHook, Step::RemoveReplaced] {
+            assert!(journal(Operation::Install, Some("1.0.0"), step).can_roll_back());
+        }
+        for step in [Step::InstallScript, Step::PostHook, Step::Record] {
+            assert!(!journal(Operation::Install, Some("1.0.0"), step).can_roll_back());
+            assert!(journal(Operation::Install, None, step).can_roll_back());
+        }
+        assert!(journal(Operation::Remove, None, Step::PostHook).can_roll_back());
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::unix::io::AsRawFd;
+
+/// The lock every command that changes the installed packages holds, so two of them never
+/// load and save the package database, or extract into the storage directory, at the same time.
+pub const LOCK_PATH: &str = "/etc/uspm/uspm.lock";
+
+/// Holds the lock until it is dropped. The lock is an flock on LOCK_PATH, which stays in place, so
+/// the kernel releases it when a uspm exits or is killed and there is never a stale lock to clean up.
+/// The file contains the pid of the uspm holding it, which is only used to say who has it.
+pub struct Lock {
+    // closing the file releases the lock
+    file: File,
+}
+
+// tries to take the flock, returning false if someone else has it
+fn try_lock(file: &File, blocking: bool) -> Result<bool, std::io::Error> {
+    let operation = if blocking { libc::LOCK_EX } else { libc::LOCK_EX | libc::LOCK_NB };
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        return Ok(true);
+    }
+    let error = std::io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+// the pid in the lock file, None if it can't be read
+fn lock_owner(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+impl Lock {
+    /// Takes the lock. If another uspm is holding it this fails with "another uspm (pid N) is running",
+    /// or with `wait` blocks until it is released.
+    pub fn acquire(wait: bool) -> Result<Self, std::io::Error> {
+        let failed = |e: std::io::Error| std::io::Error::new(e.kind(), format!("Could not lock {}: {}", LOCK_PATH, e));
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(LOCK_PATH).map_err(failed)?;
+
+        if !try_lock(&file, false).map_err(failed)? {
+            let owner = match lock_owner(&mut file) {
+                Some(pid) => format!("another uspm (pid {})", pid),
+                None => "another uspm".to_string(),
+            };
+            if !wait {
+                return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, format!("{} is running, use --wait to wait for it", owner)));
+            }
+            println!("Waiting for {} to finish", owner);
+            try_lock(&file, true).map_err(failed)?;
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(std::process::id().to_string().as_bytes())?;
+        Ok(Lock { file })
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // the pid is cleared while the lock is still held, so the file never names a uspm that has finished
+        let _ = self.file.set_len(0);
+    }
+}
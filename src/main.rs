@@ -1,14 +1,38 @@
 use std::env;
-use install::install_package;
-use crate::config::Config;
-use crate::install::uninstall_package;
-use crate::repo::{check_repo_for_package, download_repo_file};
+use std::fs::OpenOptions;
+use std::io::{IsTerminal, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use crate::atomic::create_private_dir;
+use crate::config::{Config, CONFIG_PATH};
+use crate::dephandle::find_updates;
+use crate::install::{autoremove_packages, install_packages, read_packages, recover, uninstall_packages, upgrade_packages};
+use crate::journal::Journal;
+use crate::lock::Lock;
+use crate::package::{InstallReason, PackageFile};
+use crate::repo::{find_newest_package, merge_repos, repos_for_target, repository_name, split_repository};
+use crate::search::{package_not_found, search_packages, MatchMode, SearchQuery};
+use crate::session::Session;
 
 mod install;
+mod journal;
 mod dephandle;
+mod download;
+mod atomic;
 mod config;
+mod lock;
 mod package;
 mod repo;
+mod sandbox;
+mod script;
+mod search;
+mod session;
+
+/// Exit status used by `outdated` when at least one installed package has an update available.
+const UPDATES_AVAILABLE_EXIT_CODE: i32 = 100;
+
+/// Exit status used by `outdated` when a repository couldn't be reached, so it is unknown whether there are updates.
+/// Not 101, which is what a Rust program exits with when it panics.
+const REPOSITORY_UNREACHABLE_EXIT_CODE: i32 = 102;
 
 /**
 This is a rust version of the entire USPM project. I am using this to learn Rust.
@@ -17,7 +41,17 @@ Use as uspm-rust <command> <package 1> <package 2> <package 3> ...
 #[tokio::main]
 async fn main() {
     // command line arguments
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // --set key=value changes a config setting for this run only, wherever it is on the command line
+    let mut overrides = take_overrides(&mut args);
+    // --verbose shows the output of package scripts as they run, the same as --set verbose=true
+    if args.iter().any(|arg| arg == "--verbose") {
+        args.retain(|arg| arg != "--verbose");
+        overrides.push(("verbose".to_string(), "true".to_string()));
+    }
+    // --wait makes commands that change packages wait for another uspm to finish instead of failing
+    let wait = args.iter().any(|arg| arg == "--wait");
+    args.retain(|arg| arg != "--wait");
 
     // if there are no args, print_help()
     if args.len() == 1 {
@@ -29,29 +63,89 @@ async fn main() {
     match args[1].as_str() {
         "install" => {
             // install the packages
-            // for each package, install
-            for package in args[2..].iter() {
-                install_package(package.to_string()).expect("Could not install package");
+            // --features gui,docs enables those features (and their optional dependencies) on every package named
+            let mut packages: Vec<String> = Vec::new();
+            let mut features: Vec<String> = Vec::new();
+            let mut args_iter = args[2..].iter();
+            while let Some(arg) = args_iter.next() {
+                if arg == "--features" {
+                    let list = args_iter.next().expect("--features needs a comma separated list of features");
+                    features.extend(list.split(',').filter(|f| !f.is_empty()).map(|f| f.to_string()));
+                } else {
+                    packages.push(arg.to_string());
+                }
             }
+
+            let session = load_session(&overrides);
+            let lock = lock_packages(wait, session.config());
+            let result = install_packages(&session, packages, features).await;
+            drop(lock);
+            exit_on_error(result, "Could not install package");
         },
         "remove" => {
             // remove the packages
-            for package in args[2..].iter() {
-                uninstall_package(package.to_string()).expect("Could not install package");
-            }
+            // --cascade also removes any packages that depend on them
+            let cascade = args[2..].iter().any(|arg| arg == "--cascade");
+            let packages: Vec<String> = args[2..].iter().filter(|arg| *arg != "--cascade").cloned().collect();
+            let config = load_config(&overrides);
+            let lock = lock_packages(wait, &config);
+            let result = uninstall_packages(&config, packages, cascade);
+            drop(lock);
+            exit_on_error(result, "Could not remove packages");
+        },
+        "autoremove" => {
+            // remove dependencies that nothing needs anymore
+            let config = load_config(&overrides);
+            let lock = lock_packages(wait, &config);
+            let result = autoremove_packages(&config);
+            drop(lock);
+            exit_on_error(result, "Could not remove packages");
         },
         "upgrade" => {
-            // upgrade the packages
-            for package in args[2..].iter() {
-                // this basically just tries to install the package again
-                // it won't install if the current version is greater than or equal to the version in the repo
-                install_package(package.to_string()).expect("Could not install package");
+            // upgrade the packages named, or every installed package if none are named
+            let session = load_session(&overrides);
+            let lock = lock_packages(wait, session.config());
+            let result = upgrade_packages(&session, args[2..].to_vec()).await;
+            drop(lock);
+            exit_on_error(result, "Could not upgrade packages");
+        },
+        "recover" => {
+            // finish or undo an install or removal that was interrupted
+            let resume = match args.get(2).map(|arg| arg.as_str()) {
+                Some("resume") => true,
+                Some("rollback") => false,
+                _ => {
+                    match exit_on_error(Journal::load(), "Could not read the journal") {
+                        Some(journal) => println!("Interrupted {}", journal),
+                        None => println!("No interrupted operation to recover"),
+                    }
+                    return;
+                }
+            };
+            let config = load_config(&overrides);
+            let lock = exit_on_error(Lock::acquire(wait), "Could not lock the package database");
+            let result = recover(&config, resume);
+            drop(lock);
+            exit_on_error(result, "Could not recover");
+        },
+        "outdated" | "check-updates" => {
+            // list the packages that have updates available
+            // exits with UPDATES_AVAILABLE_EXIT_CODE if there are any so monitoring can alert on it,
+            // or REPOSITORY_UNREACHABLE_EXIT_CODE if it couldn't check every repository
+            let status = exit_on_error(outdated(&load_session(&overrides)).await, "Could not check for updates");
+            if status != 0 {
+                std::process::exit(status);
             }
         },
         "search" => {
             // search for the packages
+            exit_on_error(search(&load_session(&overrides), args[2..].to_vec()).await, "Could not search packages");
+        },
+        "info" => {
+            // show everything known about the packages
+            let session = load_session(&overrides);
             for package in args[2..].iter() {
-                search(package.to_string()).await;
+                exit_on_error(info(&session, package.to_string()).await, "Could not show package");
             }
         },
         "list" => {
@@ -59,7 +153,8 @@ async fn main() {
             list_packages();
         },
         "config" => {
-            config();
+            // show or change the config
+            exit_on_error(config(args[2..].to_vec(), &overrides), "Could not update config");
         },
         "help" => {
             // print help
@@ -76,70 +171,356 @@ async fn main() {
     }
 }
 
-async fn search(package: String) {
-    // load the config file
-    let config: Config;
-    let config_result = Config::load();
-    if config_result.is_err() {
-        config = Config::default();
-        config.save().expect("Could not save default config")
-    } else {
-        config = config_result.unwrap();
-    }
-
-    for mirror in config.mirrors() {
-        let repo_result = download_repo_file(mirror).await;
-        if repo_result.is_err() {
-            println!("Could not download repo file from {}", mirror);
-            continue;
-        } else {
-            let repo = repo_result.unwrap();
-            if check_repo_for_package(repo, &package) {
-                println!("Found package {} in {}", package, mirror);
+/// Takes the package database lock for a command that changes the installed packages, exiting if another
+/// uspm has it (unless `wait` is set). The lock has to be dropped before exiting, so the config and
+/// session are loaded before it is taken, and exit_on_error is called on the command's result after it is dropped.
+/// If an earlier uspm was interrupted part way through, that has to be resumed or rolled back first.
+fn lock_packages(wait: bool, config: &Config) -> Lock {
+    let lock = exit_on_error(Lock::acquire(wait), "Could not lock the package database");
+    let result = match Journal::load() {
+        Ok(None) => return lock,
+        Ok(Some(journal)) => {
+            println!("An earlier uspm was interrupted {}", journal);
+            match ask_recovery(journal.can_roll_back()) {
+                Some(resume) => recover(config, resume),
+                None if journal.can_roll_back() => Err(std::io::Error::other("run `uspm-rust recover resume` to finish it or `uspm-rust recover rollback` to undo it")),
+                None => Err(std::io::Error::other("run `uspm-rust recover resume` to finish it")),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => lock,
+        Err(e) => {
+            drop(lock);
+            exit_on_error(Err(e), "Could not recover")
+        }
+    }
+}
+
+// asks whether to resume (true) or roll back (false) an interrupted operation, None to leave it be.
+// rolling back is only offered if `can_roll_back`. only asked on a terminal, anything else has to run the recover command
+fn ask_recovery(can_roll_back: bool) -> Option<bool> {
+    if !std::io::stdin().is_terminal() {
+        return None;
+    }
+    match can_roll_back {
+        true => print!("Resume it, roll it back or leave it? [r/b/l] "),
+        false => print!("Resume it or leave it? It can't be rolled back. [r/l] "),
+    }
+    std::io::stdout().flush().ok()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).ok()?;
+    match answer.trim() {
+        "r" => Some(true),
+        "b" if can_roll_back => Some(false),
+        _ => None,
+    }
+}
+
+/// Loads the config and sets up a session with it, exiting if either fails.
+fn load_session(overrides: &[(String, String)]) -> Session {
+    exit_on_error(Session::new(load_config(overrides)), "Could not set up the network")
+}
+
+/// Loads the config with every layer applied, exiting if it can't be loaded.
+fn load_config(overrides: &[(String, String)]) -> Config {
+    exit_on_error(Config::load_layered(overrides), "Could not load config")
+}
+
+/// Removes every `--set key=value` from the arguments and returns them as config overrides.
+fn take_overrides(args: &mut Vec<String>) -> Vec<(String, String)> {
+    let mut overrides = Vec::new();
+    while let Some(index) = args.iter().position(|arg| arg == "--set") {
+        args.remove(index);
+        let setting = if index < args.len() { args.remove(index) } else { String::new() };
+        match setting.split_once('=') {
+            Some((key, value)) => overrides.push((key.to_string(), value.to_string())),
+            None => {
+                println!("--set needs a key=value setting, not \"{}\"", setting);
+                std::process::exit(1);
             }
         }
     }
+    overrides
+}
+
+/// Prints the error and exits with a failure status instead of panicking, so messages like
+/// "did you mean" suggestions are readable.
+fn exit_on_error<T>(result: Result<T, std::io::Error>, message: &str) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            println!("{}: {}", message, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints the newest available version of a package along with its installed state.
+/// The package can be given as `repo/package` to show the version in that repository.
+async fn info(session: &Session, target: String) -> Result<(), std::io::Error> {
+    let packages = read_packages()?;
+    let repos = repos_for_target(&session.repos().await, &target)?;
+    let package = split_repository(&target).1.to_string();
+
+    let installed = packages.get_package(package.clone()).cloned();
+    let (mirror, p_file) = match find_newest_package(&repos, &package) {
+        Some((mirror, p_file)) => (repository_name(&repos, &mirror).map(|repository| (mirror, repository)), p_file),
+        None => match installed.clone() {
+            Some(p_file) => (None, p_file),
+            None => {
+                let candidates: Vec<PackageFile> = merge_repos(&repos).into_iter().map(|(_, p)| p).collect();
+                return Err(package_not_found(&package, &candidates, "was not found in any mirror"));
+            }
+        },
+    };
+
+    println!("Name: {}", p_file.name);
+    println!("Version: {}", p_file.version);
+    if let Some((mirror, repository)) = mirror {
+        println!("Repository: {}", repository);
+        println!("Mirror: {}", mirror);
+    }
+    match installed {
+        Some(installed) => println!("Installed: {} ({})", installed.version, match packages.get_install_reason(package.clone()) {
+            InstallReason::Explicit => "explicit",
+            InstallReason::Dependency => "dependency",
+        }),
+        None => println!("Installed: no"),
+    }
+    if let Some(repository) = packages.get_record(&package).and_then(|record| record.repository.as_ref()) {
+        println!("Installed from: {}", repository);
+    }
+    if !p_file.description.is_empty() {
+        println!("Description: {}", p_file.description);
+    }
+    if !p_file.license.is_empty() {
+        println!("License: {}", p_file.license);
+    }
+    if !p_file.arch.is_empty() {
+        println!("Architecture: {}", p_file.arch);
+    }
+    let mut dependencies: Vec<String> = p_file.dependencies.iter().map(|(name, min_ver)| format!("{} >= {}", name, min_ver)).collect();
+    dependencies.sort();
+    println!("Dependencies: {}", dependencies.join(", "));
+    for (label, list) in [("Provides", &p_file.provides), ("Conflicts", &p_file.conflicts), ("Replaces", &p_file.replaces)] {
+        if !list.is_empty() {
+            println!("{}: {}", label, list.join(", "));
+        }
+    }
+    let mut features: Vec<&String> = p_file.features.keys().collect();
+    features.sort();
+    let enabled = packages.get_features(package);
+    for feature in features {
+        let mut extras: Vec<&String> = p_file.features.get(feature).unwrap().keys().collect();
+        extras.sort();
+        let extras: Vec<&str> = extras.into_iter().map(|e| e.as_str()).collect();
+        let state = if enabled.contains(feature) { " (enabled)" } else { "" };
+        println!("Feature {}{}: {}", feature, state, extras.join(", "));
+    }
+    Ok(())
+}
+
+/// Searches package names and descriptions across every mirror.
+/// Patterns are substrings unless --glob or --regex is given, or they contain glob characters.
+async fn search(session: &Session, args: Vec<String>) -> Result<(), std::io::Error> {
+    let missing = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidInput, message.to_string());
+    let mut query = SearchQuery {
+        patterns: Vec::new(),
+        mode: MatchMode::Substring,
+        license: None,
+        arch: None,
+        installed: None,
+    };
+    let mut mode: Option<MatchMode> = None;
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--glob" => mode = Some(MatchMode::Glob),
+            "--regex" => mode = Some(MatchMode::Regex),
+            "--license" => query.license = Some(args_iter.next().ok_or_else(|| missing("--license needs a license name"))?.to_string()),
+            "--arch" => query.arch = Some(args_iter.next().ok_or_else(|| missing("--arch needs an architecture"))?.to_string()),
+            "--installed" => query.installed = Some(true),
+            "--not-installed" => query.installed = Some(false),
+            _ => query.patterns.push(arg.to_string()),
+        }
+    }
+    query.mode = mode.unwrap_or(if query.patterns.iter().any(|p| p.contains(['*', '?', '['])) {
+        MatchMode::Glob
+    } else {
+        MatchMode::Substring
+    });
+
+    let packages = read_packages()?;
+    let repos = session.repos().await;
+
+    let results = search_packages(&repos, &packages, &query)?;
+    if results.is_empty() {
+        println!("No packages found");
+        return Ok(());
+    }
+    for result in results {
+        match result.installed_version {
+            Some(version) => println!("{} {} [installed {}] ({})", result.package.name, result.package.version, version, result.mirror),
+            None => println!("{} {} ({})", result.package.name, result.package.version, result.mirror),
+        }
+        if !result.package.description.is_empty() {
+            println!("    {}", result.package.description);
+        }
+    }
+    Ok(())
+}
+
+/// Prints every installed package that has a newer version on a mirror.
+/// Returns the status to exit with: REPOSITORY_UNREACHABLE_EXIT_CODE if any repository couldn't be
+/// reached, otherwise UPDATES_AVAILABLE_EXIT_CODE if any updates are available and 0 if not.
+async fn outdated(session: &Session) -> Result<i32, std::io::Error> {
+    let packages = read_packages()?;
+
+    let mut names: Vec<String> = packages.get_packages().into_iter().map(|p| p.name).collect();
+    names.sort();
+
+    let repos = session.repos().await;
+    let updates = find_updates(&packages, &repos, names);
+    let unreachable = session.unreachable_repositories().await;
+
+    for update in updates.iter() {
+        let installed_version = packages.get_package(update.package.name.clone()).unwrap().version.clone();
+        println!("{} {} -> {} ({})", update.package.name, installed_version, update.package.version, update.mirror);
+    }
+    if !unreachable.is_empty() {
+        println!("Could not check for updates in {}, no mirror of them could be reached", unreachable.join(", "));
+        return Ok(REPOSITORY_UNREACHABLE_EXIT_CODE);
+    }
+    if updates.is_empty() {
+        println!("All packages are up to date!");
+        return Ok(0);
+    }
+    Ok(UPDATES_AVAILABLE_EXIT_CODE)
 }
 
 fn print_help() {
-    println!("Usage: uspm-rust <command> [<package 1> <package 2> <package 3> ...]");
+    println!("Usage: uspm-rust [--set <key>=<value> ...] [--wait] [--verbose] <command> [<package 1> <package 2> <package 3> ...]");
     println!("Commands:");
-    println!("  install <package 1> [<package 2> <package 3> ...]");
-    println!("  remove <package 1> [<package 2> <package 3> ...]");
-    println!("  upgrade");
-    println!("  search");
+    println!("  install [--features <feature 1>,<feature 2>] <package 1> [<package 2> <package 3> ...]");
+    println!("  remove [--cascade] <package 1> [<package 2> <package 3> ...]");
+    println!("  autoremove");
+    println!("  recover [resume | rollback]");
+    println!("  upgrade [<package 1> <package 2> ...]");
+    println!("  outdated (alias: check-updates), exits with status {} if updates are available", UPDATES_AVAILABLE_EXIT_CODE);
+    println!("    or {} if a repository could not be reached", REPOSITORY_UNREACHABLE_EXIT_CODE);
+    println!("  search [--glob | --regex] [--license <license>] [--arch <arch>] [--installed | --not-installed] [<pattern> ...]");
+    println!("  info <package 1> [<package 2> <package 3> ...]");
     println!("  list");
-    println!("  config");
+    println!("  config [show [--origin]]");
+    println!("  config get <key>");
+    println!("  config set <key> <value>");
+    println!("  config unset <key>");
+    println!("  config mirror add|remove <mirror>");
+    println!("  config mirror list");
+    println!("  config edit");
     println!("  help");
     println!("  version");
 }
 
-fn config() {
-    let config: Config;
-    let config_result = Config::load();
+/// Shows or changes the config. Keys are dotted paths into the config file, like `network.http_proxy`
+/// or `repositories.0.priority`, and values are JSON, falling back to a plain string.
+/// show and get give the config with every layer applied, changes only go to the config file
+/// and are validated before it is saved.
+fn config(args: Vec<String>, overrides: &[(String, String)]) -> Result<(), std::io::Error> {
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    // edit works on the file as it is, so a config that doesn't load can still be fixed with it
+    if args == ["edit"] {
+        return edit_config();
+    }
 
     // If the config file doesn't exist, create it and use the default config.
-    if config_result.is_err() {
-        config = Config::default();
-        config.save().expect("Could not save default config")
-    } else {
-        config = config_result.unwrap();
+    let mut config = Config::load_or_default()?;
+    let layered = || Config::load_layered(overrides);
+    let usage = || std::io::Error::new(std::io::ErrorKind::InvalidInput, "see uspm-rust help for the config commands");
+
+    match args.as_slice() {
+        [] | ["show"] => println!("Config:\n{}", layered()?),
+        ["show", "--origin"] => {
+            for (key, value, origin) in layered()?.settings_with_origins() {
+                println!("{} = {} ({})", key, value, origin);
+            }
+        },
+        ["get", key] => match layered()?.get(key)? {
+            serde_json::Value::String(value) => println!("{}", value),
+            value => println!("{}", serde_json::to_string_pretty(&value)?),
+        },
+        ["set", key, value] => {
+            config.set_str(key, value)?;
+            config.save()?;
+        },
+        ["unset", key] => {
+            config.unset(key)?;
+            config.save()?;
+        },
+        ["mirror", "add", mirror] => {
+            if config.mirrors().iter().any(|m| m == mirror) {
+                println!("{} is already a mirror", mirror);
+                return Ok(());
+            }
+            config.add_mirror(mirror.to_string());
+            config.save()?;
+        },
+        ["mirror", "remove", mirror] => {
+            if !config.mirrors().iter().any(|m| m == mirror) {
+                return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} is not a mirror", mirror)));
+            }
+            config.remove_mirror(mirror.to_string());
+            config.save()?;
+        },
+        ["mirror", "list"] => {
+            for mirror in config.mirrors() {
+                println!("{}", mirror);
+            }
+        },
+        _ => return Err(usage()),
+    }
+    Ok(())
+}
+
+// opens a copy of the config in $VISUAL or $EDITOR and saves it once it parses and validates,
+// so a mistake never ends up in /etc/uspm/config.json
+fn edit_config() -> Result<(), std::io::Error> {
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).unwrap_or_else(|_| "vi".to_string());
+    let contents = match std::fs::read_to_string(CONFIG_PATH) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default().to_string(),
+        contents => contents?,
+    };
+    // a directory of our own, so nobody else can read the copy, or change it between the edit and the save
+    let directory = create_private_dir("uspm-config")?;
+    let path = directory.join("config.json");
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?.write_all(contents.as_bytes())?;
+
+    // through sh so an editor with arguments, like "code --wait", works
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&path)
+        .status()?;
+    if !status.success() {
+        std::fs::remove_dir_all(&directory)?;
+        return Err(std::io::Error::other(format!("{} exited with {}, the config was not changed", editor, status)));
     }
 
-    println!("Config:\n{}", config.to_string());
+    let edited = std::fs::read_to_string(&path)?;
+    let edited = Config::parse(&edited)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} (your edit is kept in {})", e, path.display())))?;
+    edited.validate()
+        .map_err(|e| std::io::Error::new(e.kind(), format!("{} (your edit is kept in {})", e, path.display())))?;
+    edited.save()?;
+    std::fs::remove_dir_all(&directory)
 }
 
 fn list_packages() {
     // list all packages
-    let mut package_file = package::Packages::new();
-    if package_file.load().is_err() {
-        println!("Packages file could not be loaded! Making new packages file");
-        package_file.save().expect("Could not save packages file, exiting");
-        println!("New packages file, no packages installed");
-        return;
-    } else {
-        package_file.load().unwrap();
-    }
+    let package_file = exit_on_error(read_packages(), "Could not load packages file");
     for package in package_file.get_packages() {
         println!("{} {}", package.name, package.version);
     }
@@ -1,10 +1,23 @@
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Read, Write};
-use md5;
-
-use serde::__private::de;
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::atomic::{backup_paths, write_atomic, write_with_backups};
+
+/// The database of installed packages.
+pub const PACKAGES_PATH: &str = "/etc/uspm/packages.json";
+
+/// How many previous versions of the database are kept, as packages.json.1 (the newest) and up.
+pub const DATABASE_BACKUPS: usize = 5;
+
+/// The version of the database format. Databases from before the `version` key are version 1.
+pub const DATABASE_VERSION: u64 = 2;
+
+// each migration upgrades a database, in its json form, from the version at its index + 1 to the next version
+const MIGRATIONS: [fn(&mut Value); 1] = [migrate_v1];
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PackageFile {
@@ -12,6 +25,25 @@ pub struct PackageFile {
     pub(crate) version: String,
     pub dependencies: HashMap<String, String>,
     pub checksum: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub license: String,
+    // the architecture the package is built for, empty or "any" if it runs anywhere
+    #[serde(default)]
+    pub arch: String,
+    // packages that can't be installed at the same time as this one
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    // virtual capabilities (like sh or mta) this package supplies that other packages can depend on
+    #[serde(default)]
+    pub provides: Vec<String>,
+    // old or renamed packages this one supersedes, they get removed when this is installed
+    #[serde(default)]
+    pub replaces: Vec<String>,
+    // optional dependencies grouped by feature name, only installed when the feature is enabled
+    #[serde(default)]
+    pub features: HashMap<String, HashMap<String, String>>,
 }
 
 
@@ -22,42 +54,138 @@ impl Clone for PackageFile {
             version: self.version.clone(),
             dependencies: self.dependencies.clone(),
             checksum: self.checksum.clone(),
+            description: self.description.clone(),
+            license: self.license.clone(),
+            arch: self.arch.clone(),
+            conflicts: self.conflicts.clone(),
+            provides: self.provides.clone(),
+            replaces: self.replaces.clone(),
+            features: self.features.clone(),
         }
     }
 }
 
 
+/// Why a package was installed. Packages installed only to satisfy another package's dependencies
+/// can be cleaned up by `autoremove` once nothing needs them anymore.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallReason {
+    Explicit,
+    Dependency,
+}
+
+/// An installed package along with how and when it was installed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InstalledPackage {
+    pub package: PackageFile,
+    pub reason: InstallReason,
+    // enabled features, kept so upgrades install the same extras
+    #[serde(default)]
+    pub features: Vec<String>,
+    // seconds since the epoch that the installed version was installed at.
+    // this and the origin are None for packages installed before they were recorded
+    #[serde(default)]
+    pub installed_at: Option<u64>,
+    // the repository the package was installed from
+    #[serde(default)]
+    pub repository: Option<String>,
+    // the md5 digest of the archive the package was installed from
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Packages {
-    packages: HashMap<String, PackageFile>,
+    version: u64,
+    packages: HashMap<String, InstalledPackage>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
 }
 
 impl Packages {
     pub fn new() -> Self {
         Packages {
+            version: DATABASE_VERSION,
             packages: HashMap::new(),
         }
     }
 
+    /// Records a package as installed now, explicitly and with no features until told otherwise.
     pub fn add_package(&mut self, name: String, package: PackageFile) {
-        self.packages.insert(name, package);
+        self.packages.insert(name, InstalledPackage {
+            package,
+            reason: InstallReason::Explicit,
+            features: Vec::new(),
+            installed_at: Some(now()),
+            repository: None,
+            digest: None,
+        });
     }
 
     pub fn get_packages(&self) -> Vec<PackageFile> {
-        self.packages.values().cloned().collect()
+        self.packages.values().map(|record| record.package.clone()).collect()
     }
 
     pub fn remove_package(&mut self, name: String) {
         self.packages.remove(&name);
     }
 
+    pub fn get_install_reason(&self, name: String) -> InstallReason {
+        self.packages.get(&name).map(|record| record.reason).unwrap_or(InstallReason::Explicit)
+    }
+
+    pub fn set_install_reason(&mut self, name: String, reason: InstallReason) {
+        if let Some(record) = self.packages.get_mut(&name) {
+            record.reason = reason;
+        }
+    }
+
     pub fn get_package(&self, name: String) -> Option<&PackageFile> {
-        self.packages.get(&name)
+        self.packages.get(&name).map(|record| &record.package)
+    }
+
+    /// Everything recorded about an installed package.
+    pub fn get_record(&self, name: &str) -> Option<&InstalledPackage> {
+        self.packages.get(name)
     }
 
+    /// Records a new version of an installed package, keeping its reason and features.
     pub fn replace_package(&mut self, name: String, package: PackageFile) {
-        self.packages.remove(&name);
-        self.packages.insert(name, package);
+        match self.packages.get_mut(&name) {
+            Some(record) => {
+                record.package = package;
+                record.installed_at = Some(now());
+            }
+            None => self.add_package(name, package),
+        }
+    }
+
+    /// Records where an installed package came from: the repository and the digest of its archive.
+    pub fn set_origin(&mut self, name: &str, repository: Option<String>, digest: Option<String>) {
+        if let Some(record) = self.packages.get_mut(name) {
+            record.repository = repository;
+            record.digest = digest;
+        }
+    }
+
+    pub fn get_features(&self, name: String) -> Vec<String> {
+        self.packages.get(&name).map(|record| record.features.clone()).unwrap_or_default()
+    }
+
+    pub fn set_features(&mut self, name: String, mut features: Vec<String>) {
+        features.sort();
+        features.dedup();
+        if let Some(record) = self.packages.get_mut(&name) {
+            record.features = features;
+        }
+    }
+
+    /// The dependencies of an installed package, including those of its enabled features.
+    pub fn dependencies_of(&self, package: &PackageFile) -> HashMap<String, String> {
+        package.dependencies_with_features(&self.get_features(package.name.clone()))
     }
 
     pub fn has_package(&mut self, name: String) -> bool {
@@ -65,23 +193,128 @@ impl Packages {
         self.packages.contains_key(name.as_str())
     }
 
+    /// Saves the database without ever leaving it half written, keeping the previous one as a backup.
     pub fn save(&self) -> Result<(), std::io::Error> {
-        let mut file = File::create("/etc/uspm/packages.json")?;
         let serialized = serde_json::to_string_pretty(self)?;
-        file.write_all(serialized.as_bytes())?;
-        Ok(())
+        write_with_backups(PACKAGES_PATH, serialized.as_bytes(), DATABASE_BACKUPS)
     }
 
+    /// Loads the database. If it is missing or can't be parsed, the newest backup that can
+    /// is put back in its place, and the broken database is kept next to it. A database written
+    /// by a newer uspm is an error, and is never replaced.
+    /// A database from an older version of uspm is upgraded and saved in the current format.
+    /// This writes the database, so it is only for commands that hold the package database lock.
     pub fn load(&mut self) -> Result<(), std::io::Error> {
-        let mut file = File::open("/etc/uspm/packages.json")?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        let packages: Packages = serde_json::from_str(&contents)?;
+        let (packages, version) = match read_database(PACKAGES_PATH) {
+            Ok(read) => read,
+            Err(e) => restore_database(e, true)?,
+        };
+        self.version = packages.version;
+        self.packages = packages.packages;
+
+        if version < DATABASE_VERSION {
+            // the old database is kept as the newest backup
+            match self.save() {
+                Ok(()) => println!("Upgraded the package database from version {} to {}", version, DATABASE_VERSION),
+                Err(e) => println!("Could not save the upgraded package database: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the database like `load`, but never writes it: an older version is only upgraded
+    /// in memory, and a broken database is read from its newest usable backup and left in place.
+    pub fn read(&mut self) -> Result<(), std::io::Error> {
+        let (packages, _) = match read_database(PACKAGES_PATH) {
+            Ok(read) => read,
+            Err(e) => restore_database(e, false)?,
+        };
+        self.version = packages.version;
         self.packages = packages.packages;
         Ok(())
     }
 }
 
+// reads a database, migrating it to the current version. also returns the version it was in
+fn read_database(path: &str) -> Result<(Packages, u64), std::io::Error> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path, e));
+    let contents = std::fs::read_to_string(path)?;
+    let mut database: Value = serde_json::from_str(&contents).map_err(|e| invalid(e.to_string()))?;
+
+    let version = match database.get("version") {
+        Some(version) => version.as_u64().filter(|v| *v >= 1).ok_or_else(|| invalid("version is not a version number".to_string()))?,
+        None => 1,
+    };
+    if version > DATABASE_VERSION {
+        // not broken, just written by a newer uspm, so this is never treated like a corrupt database
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported,
+            format!("{}: database version {} is newer than this uspm understands ({})", path, version, DATABASE_VERSION)));
+    }
+    if version == DATABASE_VERSION {
+        let packages = serde_json::from_str(&contents).map_err(|e| invalid(e.to_string()))?;
+        return Ok((packages, version));
+    }
+
+    for migration in MIGRATIONS[version as usize - 1..].iter() {
+        migration(&mut database);
+    }
+    database["version"] = Value::from(DATABASE_VERSION);
+    let packages = serde_json::from_value(database).map_err(|e| invalid(e.to_string()))?;
+    Ok((packages, version))
+}
+
+// version 1 databases kept the package files, install reasons and features in separate maps keyed by name.
+// when and where those packages were installed from was never recorded, so that is left unknown
+fn migrate_v1(database: &mut Value) {
+    let reasons = database["install_reasons"].take();
+    let features = database["features"].take();
+    let packages = match database["packages"].take() {
+        Value::Object(packages) => packages,
+        _ => Default::default(),
+    };
+
+    let records: serde_json::Map<String, Value> = packages.into_iter().map(|(name, package)| {
+        let record = json!({
+            "package": package,
+            "reason": reasons.get(&name).cloned().unwrap_or(json!("explicit")),
+            "features": features.get(&name).cloned().unwrap_or(json!([])),
+            "installed_at": null,
+            "repository": null,
+            "digest": null,
+        });
+        (name, record)
+    }).collect();
+    *database = json!({ "packages": records });
+}
+
+// returns the newest backup that still loads in place of a database that can't be read or parsed, and
+// with `restore` puts it back in the database's place. with no usable backup the original error is
+// returned, so a missing database stays NotFound. a database from a newer uspm is left alone, since
+// its backups are older than what it holds
+fn restore_database(error: std::io::Error, restore: bool) -> Result<(Packages, u64), std::io::Error> {
+    if error.kind() == std::io::ErrorKind::Unsupported {
+        return Err(error);
+    }
+    for backup in backup_paths(PACKAGES_PATH, DATABASE_BACKUPS) {
+        let Ok(read) = read_database(&backup) else {
+            continue;
+        };
+        if !restore {
+            println!("The package database could not be loaded ({}), using {} instead", error, backup);
+            return Ok(read);
+        }
+        if error.kind() != std::io::ErrorKind::NotFound {
+            let corrupt = format!("{}.corrupt.{}", PACKAGES_PATH, now());
+            std::fs::rename(PACKAGES_PATH, &corrupt)?;
+            println!("Kept the broken package database as {}", corrupt);
+        }
+        write_atomic(PACKAGES_PATH, &std::fs::read(&backup)?)?;
+        println!("The package database could not be loaded ({}), restored it from {}", error, backup);
+        return Ok(read);
+    }
+    Err(error)
+}
+
 impl PackageFile {
     pub fn load(path: String) -> Result<Self, std::io::Error> {
         let mut file = File::open(path)?;
@@ -98,38 +331,107 @@ impl PackageFile {
         hex == hash
     }
 
+    #[allow(dead_code)]
     pub fn default() -> Self {
         let mut dependencies = HashMap::new();
         dependencies.insert("uspm".to_string(), "1.0.0".to_string());
         PackageFile {
             name: "test".to_string(),
             version: "0.0.0".to_string(),
-            dependencies: dependencies,
+            dependencies,
             checksum: "".to_string(),
+            description: "".to_string(),
+            license: "".to_string(),
+            arch: "".to_string(),
+            conflicts: Vec::new(),
+            provides: Vec::new(),
+            replaces: Vec::new(),
+            features: HashMap::new(),
         }
     }
 
+    /// The package's dependencies along with the optional dependencies of the given features.
+    /// Features the package doesn't have are ignored. If a dependency is needed more than once,
+    /// the highest minimum version wins.
+    pub fn dependencies_with_features(&self, features: &[String]) -> HashMap<String, String> {
+        let mut dependencies = self.dependencies.clone();
+        for feature in features {
+            if let Some(feature_dependencies) = self.features.get(feature) {
+                for (name, min_ver) in feature_dependencies {
+                    let higher = match dependencies.get(name) {
+                        Some(current) => compare_versions(min_ver.clone(), current.clone()) > 0,
+                        None => true,
+                    };
+                    if higher {
+                        dependencies.insert(name.clone(), min_ver.clone());
+                    }
+                }
+            }
+        }
+        dependencies
+    }
+
+    /// Whether this package satisfies a dependency on `name`.
+    /// A package that replaces another also stands in for it.
+    pub fn satisfies(&self, name: &str) -> bool {
+        self.name == name || self.provides.iter().any(|p| p == name) || self.replaces.iter().any(|r| r == name)
+    }
+
+    /// Whether this package and `other` can't be installed together, checked in both directions.
+    /// A package never conflicts with one it replaces, since installing it removes the old one.
+    pub fn conflicts_with(&self, other: &PackageFile) -> bool {
+        if self.replaces.contains(&other.name) || other.replaces.contains(&self.name) {
+            return false;
+        }
+        let matches = |conflict: &String, package: &PackageFile| package.name == *conflict || package.provides.contains(conflict);
+        self.conflicts.iter().any(|c| matches(c, other)) || other.conflicts.iter().any(|c| matches(c, self))
+    }
+
+    /// The first version in this package file that compare_versions can't read, either its own or
+    /// the minimum version of one of its dependencies.
+    pub fn invalid_version(&self) -> Option<&String> {
+        let dependencies = self.dependencies.values().chain(self.features.values().flat_map(|f| f.values()));
+        std::iter::once(&self.version).chain(dependencies).find(|version| !is_valid_version(version))
+    }
+
     pub fn check(&self) -> bool {
         // check if name is empty
         if self.name.is_empty() {
             return false;
         }
-        // check if version is empty
-        if self.version.is_empty() {
+        // check if version is a valid version
+        if !is_valid_version(&self.version) {
             return false;
         }
-        // check if any of the values in dependencies are empty
-        for (key, value) in self.dependencies.iter() {
+        // check if any of the values in dependencies (or the optional dependencies of features) are empty
+        let re = Regex::new(r"^\d+\.\d+\.\d+$").unwrap();
+        if self.features.keys().any(|feature| feature.is_empty()) {
+            return false;
+        }
+        for (key, value) in self.dependencies.iter().chain(self.features.values().flatten()) {
             if key.is_empty() || value.is_empty() {
                 return false;
             }
 
             // check if value doesn't match valid version format (#.#.#) using regex
-            let re = Regex::new(r"^\d+\.\d+\.\d+$").unwrap();
             if !re.is_match(value) {
                 return false;
             }
         }
+        // check that conflicts, provides and replaces don't have empty names or refer to the package itself
+        for relation in [&self.conflicts, &self.provides, &self.replaces] {
+            for name in relation.iter() {
+                if name.is_empty() || name == &self.name {
+                    return false;
+                }
+            }
+        }
+        // a package can't conflict with something it provides or replaces
+        for conflict in self.conflicts.iter() {
+            if self.provides.contains(conflict) || self.replaces.contains(conflict) {
+                return false;
+            }
+        }
         // check if checksum is empty
         if !self.checksum.is_empty() && self.checksum.as_str() != "leave_blank_in_package_file" {
             return false;
@@ -138,6 +440,12 @@ impl PackageFile {
     }
 }
 
+/// Whether `version` is a major.minor.patch version of plain numbers, the only kind compare_versions can compare.
+pub fn is_valid_version(version: &str) -> bool {
+    let parts: Vec<&str> = version.split('.').collect();
+    parts.len() == 3 && parts.iter().all(|part| part.bytes().all(|b| b.is_ascii_digit()) && part.parse::<i32>().is_ok())
+}
+
 pub fn compare_versions(a: String, b: String) -> i8 {
     // versions use semantic versioning ex: 1.2.3
 
@@ -168,4 +476,60 @@ pub fn compare_versions(a: String, b: String) -> i8 {
     }
 
     0
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_versions_part_by_part() {
+        assert_eq!(compare_versions("1.10.0".to_string(), "1.9.0".to_string()), 1);
+        assert_eq!(compare_versions("1.0.0".to_string(), "1.0.1".to_string()), -1);
+        assert_eq!(compare_versions("2.0.0".to_string(), "2.0.0".to_string()), 0);
+    }
+
+    #[test]
+    fn only_major_minor_patch_versions_are_valid() {
+        assert!(is_valid_version("1.2.3"));
+        for version in ["1.0", "1.0.0-rc1", "1.0.0.0", "", "+1.0.0", "a.b.c"] {
+            assert!(!is_valid_version(version), "{}", version);
+        }
+    }
+
+    #[test]
+    fn migrates_version_1_databases() {
+        let path = std::env::temp_dir().join(format!("uspm-test-packages-{}.json", std::process::id()));
+        let v1 = json!({
+            "packages": {
+                "app": { "name": "app", "version": "1.0.0", "dependencies": {}, "checksum": "" },
+                "lib": { "name": "lib", "version": "2.0.0", "dependencies": {}, "checksum": "" }
+            },
+            "install_reasons": { "lib": "dependency" },
+            "features": { "app": ["docs"] }
+        });
+        std::fs::write(&path, v1.to_string()).unwrap();
+        let read = read_database(path.to_str().unwrap());
+        let _ = std::fs::remove_file(&path);
+
+        let (packages, version) = read.unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(packages.version, DATABASE_VERSION);
+        assert_eq!(packages.get_package("lib".to_string()).unwrap().version, "2.0.0");
+        assert_eq!(packages.get_install_reason("app".to_string()), InstallReason::Explicit);
+        assert_eq!(packages.get_install_reason("lib".to_string()), InstallReason::Dependency);
+        assert_eq!(packages.get_features("app".to_string()), ["docs"]);
+        assert!(packages.get_record("app").unwrap().installed_at.is_none());
+    }
+
+    #[test]
+    fn never_restores_backups_over_newer_databases() {
+        let path = std::env::temp_dir().join(format!("uspm-test-newer-packages-{}.json", std::process::id()));
+        std::fs::write(&path, json!({ "version": DATABASE_VERSION + 1, "packages": {} }).to_string()).unwrap();
+        let read = read_database(path.to_str().unwrap());
+        let _ = std::fs::remove_file(&path);
+
+        let error = read.err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+        assert_eq!(restore_database(error, true).err().unwrap().kind(), std::io::ErrorKind::Unsupported);
+    }
+}
@@ -1,30 +1,186 @@
 use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use crate::atomic::create_private_dir;
 use crate::package;
+use crate::session::Session;
 use serde::{Serialize, Deserialize};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Repo {
-  pub packages: HashMap<String, package::PackageFile>
+  pub packages: HashMap<String, package::PackageFile>,
+  // the configured repository this was downloaded for, which isn't part of repo.json
+  #[serde(skip)]
+  pub name: String,
+  #[serde(skip)]
+  pub priority: i32,
 }
 
 impl Clone for Repo {
   fn clone(&self) -> Self {
     Repo {
-      packages: self.packages.clone()
+      packages: self.packages.clone(),
+      name: self.name.clone(),
+      priority: self.priority,
     }
   }
 }
 
-pub async fn download_repo_file(mirror: &String) -> Result<Repo, reqwest::Error>{
-  let repo_url = mirror.to_string() + "/repo.json";
-  let response = reqwest::get(repo_url)
-      .await?;
-  let repo_file = response.json::<Repo>().await?;
+/// Splits `repo/package` into the repository and package names. Plain package names have no repository.
+pub fn split_repository(target: &str) -> (Option<&str>, &str) {
+  match target.split_once('/') {
+    Some((repository, package)) => (Some(repository), package),
+    None => (None, target),
+  }
+}
+
+/// Works out which repos to look in for an install target, which is either `package` or `repo/package`.
+pub fn repos_for_target(repos: &[(String, Repo)], target: &str) -> Result<Vec<(String, Repo)>, std::io::Error> {
+  let repository = split_repository(target).0;
+  let repos: Vec<(String, Repo)> = repos.iter()
+      .filter(|(_, repo)| repository.is_none_or(|name| repo.name == name))
+      .cloned()
+      .collect();
+  if let Some(repository) = repository {
+    if repos.is_empty() {
+      return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("Repository {} is not configured or could not be reached", repository)));
+    }
+  }
+  Ok(repos)
+}
+
+// checks a detached signature with gpgv, the same way packages are extracted with tar.
+// the repo file is piped in, so gpgv checks exactly the bytes that get parsed, and the signature
+// is written to a private directory where nobody else can swap it out
+fn verify_signature(keyring: &str, contents: &[u8], signature: &[u8]) -> Result<(), std::io::Error> {
+  let directory = create_private_dir("uspm-repo")?;
+  let signature_path = directory.join("repo.json.sig");
+  let status = OpenOptions::new()
+      .write(true)
+      .create_new(true)
+      .mode(0o600)
+      .open(&signature_path)
+      .and_then(|mut file| file.write_all(signature))
+      .and_then(|_| run_gpgv(keyring, &signature_path, contents));
+  let _ = std::fs::remove_dir_all(&directory);
+
+  match status {
+    Ok(status) if status.success() => Ok(()),
+    Ok(_) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Repo file signature does not verify")),
+    Err(e) => Err(std::io::Error::other(format!("Could not run gpgv to verify the repo file: {}", e))),
+  }
+}
+
+fn run_gpgv(keyring: &str, signature_path: &Path, contents: &[u8]) -> Result<ExitStatus, std::io::Error> {
+  let mut child = Command::new("gpgv")
+      .arg("--keyring")
+      .arg(keyring)
+      .arg(signature_path)
+      .arg("-")
+      .stdin(Stdio::piped())
+      .stdout(Stdio::null())
+      .stderr(Stdio::null())
+      .spawn()?;
+  // gpgv can stop reading early on a bad signature, which is for its exit status to report
+  let _ = child.stdin.take().unwrap().write_all(contents);
+  child.wait()
+}
+
+/// Downloads and parses `<base_url>/repo.json`. If the repository has a signing key,
+/// `<base_url>/repo.json.sig` has to verify against it.
+pub async fn download_repo_file(session: &Session, base_url: &String, signing_key: Option<&String>) -> Result<Repo, std::io::Error>{
+  let repo_url = base_url.to_string() + "/repo.json";
+  let response = session.get(&repo_url)
+      .send()
+      .await
+      .and_then(|r| r.error_for_status())
+      .map_err(std::io::Error::other)?;
+  let contents = response.bytes().await.map_err(std::io::Error::other)?;
+
+  if let Some(keyring) = signing_key {
+    let signature = session.get(&(repo_url.clone() + ".sig"))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(std::io::Error::other)?
+        .bytes()
+        .await
+        .map_err(std::io::Error::other)?;
+    verify_signature(keyring, &contents, &signature)?;
+  }
+
+  let mut repo_file: Repo = serde_json::from_slice(&contents)?;
+  // versions are compared all over the place, so packages with versions that can't be are left out up front
+  repo_file.packages.retain(|name, package| match package.invalid_version() {
+    Some(version) => {
+      println!("Skipping {} in {}: \"{}\" is not a major.minor.patch version", name, repo_url, version);
+      false
+    }
+    None => true,
+  });
   Ok(repo_file)
 }
 
-pub fn check_repo_for_package(repo_file: Repo, package: &String) -> bool {
-  return repo_file.packages.contains_key(&*package);
+/// Finds the newest version of a package across all of the given repos.
+/// A repository with a higher priority wins over a newer version in a lower priority one.
+/// Returns the mirror it was found on along with its package file.
+pub fn find_newest_package(repos: &[(String, Repo)], name: &str) -> Option<(String, package::PackageFile)> {
+  let mut newest: Option<(String, package::PackageFile, i32)> = None;
+  for (mirror, repo) in repos {
+    if let Some(package_file) = repo.get_package(name.to_string()) {
+      let is_newer = match &newest {
+        Some((_, current, priority)) => repo.priority > *priority
+            || (repo.priority == *priority && package::compare_versions(package_file.version.clone(), current.version.clone()) > 0),
+        None => true,
+      };
+      if is_newer {
+        newest = Some((mirror.clone(), package_file.clone(), repo.priority));
+      }
+    }
+  }
+  newest.map(|(mirror, package_file, _)| (mirror, package_file))
+}
+
+/// The name of the repository whose repo was downloaded from `mirror`.
+pub fn repository_name(repos: &[(String, Repo)], mirror: &str) -> Option<String> {
+  repos.iter().find(|(url, _)| url == mirror).map(|(_, repo)| repo.name.clone())
+}
+
+/// Merges the repos into one index holding the newest version of every package, sorted by name.
+pub fn merge_repos(repos: &[(String, Repo)]) -> Vec<(String, package::PackageFile)> {
+  let mut names: Vec<&String> = repos.iter().flat_map(|(_, repo)| repo.packages.keys()).collect();
+  names.sort();
+  names.dedup();
+  names.into_iter().filter_map(|name| find_newest_package(repos, name)).collect()
+}
+
+/// Finds the package that satisfies a dependency on `name`, preferring a package with that exact name
+/// and otherwise falling back to one that provides or replaces it.
+pub fn find_provider(repos: &[(String, Repo)], name: &str) -> Option<(String, package::PackageFile)> {
+  if let Some(found) = find_newest_package(repos, name) {
+    return Some(found);
+  }
+  let mut providers: Vec<String> = repos.iter()
+      .flat_map(|(_, repo)| repo.packages.values())
+      .filter(|p| p.satisfies(name))
+      .map(|p| p.name.clone())
+      .collect();
+  providers.sort();
+  providers.first().and_then(|provider| find_newest_package(repos, provider))
+}
+
+/// Finds a package that declares it replaces `name`, used to migrate renamed packages.
+pub fn find_replacement(repos: &[(String, Repo)], name: &str) -> Option<(String, package::PackageFile)> {
+  let mut replacements: Vec<String> = repos.iter()
+      .flat_map(|(_, repo)| repo.packages.values())
+      .filter(|p| p.replaces.iter().any(|r| r == name))
+      .map(|p| p.name.clone())
+      .collect();
+  replacements.sort();
+  replacements.first().and_then(|replacement| find_newest_package(repos, replacement))
 }
 
 impl Repo {
@@ -32,12 +188,15 @@ impl Repo {
         self.packages.get(&name)
     }
 
+    #[allow(dead_code)]
     pub fn default() -> Self {
       let package_file = package::PackageFile::default();
       let mut packages = HashMap::new();
       packages.insert("test".to_string(), package_file);
       Repo {
-        packages: packages
+        packages,
+        name: "test".to_string(),
+        priority: 0,
       }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,141 @@
+use std::ffi::CString;
+use std::ptr::null;
+
+// the per-mount options in /proc/self/mountinfo that a read-only remount has to keep
+const KEPT_OPTIONS: [(&str, libc::c_ulong); 6] = [
+    ("nosuid", libc::MS_NOSUID),
+    ("nodev", libc::MS_NODEV),
+    ("noexec", libc::MS_NOEXEC),
+    ("noatime", libc::MS_NOATIME),
+    ("nodiratime", libc::MS_NODIRATIME),
+    ("relatime", libc::MS_RELATIME),
+];
+
+/// Where a sandboxed package script runs: its own mount, ipc and uts namespaces, in which every
+/// mount is read-only except the install root, and /tmp is an empty tmpfs of its own.
+pub struct Sandbox {
+    install_root: CString,
+    // false when the install root is in /tmp, which a fresh tmpfs would hide
+    private_tmp: bool,
+    // the mounts to make read-only, with the flags they already have
+    read_only: Vec<(CString, libc::c_ulong)>,
+}
+
+// a path from mountinfo, where spaces, tabs, newlines and backslashes are escaped as octal
+fn unescape(path: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = path;
+    while let Some(index) = rest.find('\\') {
+        unescaped.push_str(&rest[..index]);
+        match rest.get(index + 1..index + 4).and_then(|octal| u8::from_str_radix(octal, 8).ok()) {
+            Some(byte) => {
+                unescaped.push(byte as char);
+                rest = &rest[index + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    unescaped + rest
+}
+
+// whether `path` is `directory` or inside it
+fn is_within(path: &str, directory: &str) -> bool {
+    directory == "/" || path == directory || path.starts_with(&(directory.to_string() + "/"))
+}
+
+fn c_string(path: &str) -> Result<CString, std::io::Error> {
+    CString::new(path).map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} contains a nul byte", path)))
+}
+
+fn check(result: libc::c_int) -> Result<(), std::io::Error> {
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+impl Sandbox {
+    /// Prepares a sandbox in which only `install_root` can be written to, which the config never lets be /.
+    /// Everything that needs allocating is done here, before the script's process is forked.
+    pub fn new(install_root: &str) -> Result<Self, std::io::Error> {
+        let install_root = install_root.trim_end_matches('/');
+        let install_root = if install_root.is_empty() { "/" } else { install_root };
+        let private_tmp = !is_within(install_root, "/tmp");
+
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+        let mut read_only = Vec::new();
+        for line in mountinfo.lines() {
+            // id, parent id, device, root, mount point, options, ...
+            let fields: Vec<&str> = line.split(' ').collect();
+            if fields.len() < 6 {
+                continue;
+            }
+            let mount_point = unescape(fields[4]);
+            if is_within(&mount_point, install_root) || (private_tmp && is_within(&mount_point, "/tmp")) {
+                continue;
+            }
+            let options: Vec<&str> = fields[5].split(',').collect();
+            let flags = KEPT_OPTIONS.iter()
+                .filter(|(option, _)| options.contains(option))
+                .fold(0, |flags, (_, flag)| flags | flag);
+            read_only.push((c_string(&mount_point)?, flags));
+        }
+
+        Ok(Sandbox {
+            install_root: c_string(install_root)?,
+            private_tmp,
+            read_only,
+        })
+    }
+
+    /// Moves the calling process into the sandbox. This runs in the forked child before it executes
+    /// the script, where only system calls are safe, so it doesn't allocate.
+    pub fn enter(&self) -> Result<(), std::io::Error> {
+        unsafe {
+            check(libc::unshare(libc::CLONE_NEWNS | libc::CLONE_NEWIPC | libc::CLONE_NEWUTS))?;
+            // keep the mounts below from propagating back to the rest of the system
+            check(libc::mount(null(), c"/".as_ptr(), null(), libc::MS_REC | libc::MS_PRIVATE, null()))?;
+            // a mount of its own, so it stays writable when what it is on becomes read-only
+            if self.install_root.as_bytes() != b"/" {
+                check(libc::mount(self.install_root.as_ptr(), self.install_root.as_ptr(), null(), libc::MS_BIND | libc::MS_REC, null()))?;
+            }
+            if self.private_tmp {
+                check(libc::mount(c"tmpfs".as_ptr(), c"/tmp".as_ptr(), c"tmpfs".as_ptr(), libc::MS_NOSUID | libc::MS_NODEV, null()))?;
+            }
+            for (mount_point, flags) in self.read_only.iter() {
+                let flags = libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | flags;
+                match check(libc::mount(null(), mount_point.as_ptr(), null(), flags, null())) {
+                    // mounts that went away, or are hidden under another one, are fine to skip
+                    Err(e) if matches!(e.raw_os_error(), Some(libc::ENOENT) | Some(libc::EINVAL)) => {}
+                    result => result?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescapes_mountinfo_paths() {
+        assert_eq!(unescape("/mnt/my\\040disk"), "/mnt/my disk");
+        assert_eq!(unescape("/a\\011b\\134c"), "/a\tb\\c");
+        assert_eq!(unescape("/plain"), "/plain");
+        // a backslash that doesn't start an octal escape is kept as it is
+        assert_eq!(unescape("/odd\\x"), "/odd\\x");
+    }
+
+    #[test]
+    fn only_counts_whole_path_components_as_within() {
+        assert!(is_within("/srv/root", "/srv/root"));
+        assert!(is_within("/srv/root/usr", "/srv/root"));
+        assert!(!is_within("/srv/rootfs", "/srv/root"));
+        assert!(is_within("/anything", "/"));
+    }
+}
@@ -0,0 +1,235 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::config::Config;
+use crate::sandbox::Sandbox;
+
+/// Where the output of package scripts is logged, in one file per package.
+pub const LOG_DIRECTORY: &str = "/var/log/uspm";
+
+/// The environment package scripts run with. Nothing is inherited from the environment uspm runs in.
+pub const SCRIPT_ENVIRONMENT: [(&str, &str); 5] = [
+    ("PATH", "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"),
+    ("HOME", "/root"),
+    ("SHELL", "/bin/sh"),
+    ("TMPDIR", "/tmp"),
+    ("LC_ALL", "C"),
+];
+
+/// The variables uspm exports to every script on top of SCRIPT_ENVIRONMENT. They all start with
+/// USPM_PKG_ so they never get mistaken for the `USPM_*` config settings when a script runs uspm itself.
+/// - USPM_PKG_NAME: the name of the package
+/// - USPM_PKG_VERSION: the version being installed, or the version being removed
+/// - USPM_PKG_PREVIOUS_VERSION: the version installed before, empty on a fresh install
+/// - USPM_PKG_INSTALL_ROOT: the directory the package installs into, `install_root` in the config
+/// - USPM_PKG_DIR: the directory the package was extracted to, which the script runs in
+/// - USPM_PKG_OPERATION: install, upgrade (to any other version), reinstall (of the same version, when
+///   a rollback puts a removed or replaced package back) or remove
+pub const SCRIPT_VARIABLES: [&str; 6] = [
+    "USPM_PKG_NAME",
+    "USPM_PKG_VERSION",
+    "USPM_PKG_PREVIOUS_VERSION",
+    "USPM_PKG_INSTALL_ROOT",
+    "USPM_PKG_DIR",
+    "USPM_PKG_OPERATION",
+];
+
+// how often a running script is checked on, to kill it once it is over the timeout
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// how long the rest of a script's output is waited for once it has exited
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The scripts a package can ship in its archive. install.sh and uninstall.sh do the work, and the
+/// hooks, which are optional, run before and after them. Every script gets the old and new versions
+/// of the package as its two arguments, with an empty string for a version there isn't
+/// (there is no old version on a fresh install, and no new version on a removal).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Script {
+    Install,
+    Uninstall,
+    PreInstall,
+    PostInstall,
+    PreUpgrade,
+    PostUpgrade,
+    PreRemove,
+    PostRemove,
+}
+
+impl Script {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Script::Install => "install.sh",
+            Script::Uninstall => "uninstall.sh",
+            Script::PreInstall => "pre_install.sh",
+            Script::PostInstall => "post_install.sh",
+            Script::PreUpgrade => "pre_upgrade.sh",
+            Script::PostUpgrade => "post_upgrade.sh",
+            Script::PreRemove => "pre_remove.sh",
+            Script::PostRemove => "post_remove.sh",
+        }
+    }
+
+    fn is_hook(&self) -> bool {
+        !matches!(self, Script::Install | Script::Uninstall)
+    }
+
+    /// The hooks run before and after install.sh, the upgrade ones if another version was installed.
+    pub fn install_hooks(upgrade: bool) -> (Script, Script) {
+        if upgrade {
+            (Script::PreUpgrade, Script::PostUpgrade)
+        } else {
+            (Script::PreInstall, Script::PostInstall)
+        }
+    }
+}
+
+/// Runs one of the scripts of a package extracted in the storage directory, failing if it exits with
+/// a nonzero status or runs longer than the configured timeout. Hooks the package doesn't ship are skipped.
+/// The script runs in the package's directory with SCRIPT_ENVIRONMENT and SCRIPT_VARIABLES, sandboxed if the config says so.
+/// Its output is appended to the package's log in LOG_DIRECTORY, and shown as it is written too if the config is verbose.
+pub fn run_script(config: &Config, package: &str, script: Script, old_version: Option<&str>, new_version: Option<&str>) -> Result<(), std::io::Error> {
+    let directory = config.storage_location().to_string() + "/" + package;
+    let path = directory.clone() + "/" + script.file_name();
+    if script.is_hook() && !Path::new(&path).exists() {
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(LOG_DIRECTORY)?;
+    let log_path = format!("{}/{}.log", LOG_DIRECTORY, package);
+    let mut log = OpenOptions::new().create(true).append(true).open(&log_path)?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    writeln!(log, "==> {} {} -> {} at {}", script.file_name(), old_version.unwrap_or("none"), new_version.unwrap_or("none"), timestamp)?;
+
+    let mut command = Command::new("sh");
+    command.arg(&path)
+        .arg(old_version.unwrap_or(""))
+        .arg(new_version.unwrap_or(""))
+        .current_dir(&directory)
+        .env_clear()
+        .envs(SCRIPT_ENVIRONMENT)
+        .envs(SCRIPT_VARIABLES.into_iter().zip([
+            package,
+            new_version.or(old_version).unwrap_or(""),
+            old_version.unwrap_or(""),
+            config.install_root(),
+            &directory,
+            operation(old_version, new_version),
+        ]))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // its own process group, so a timeout kills whatever it started too
+        .process_group(0);
+    if config.scripts().sandbox {
+        let sandbox = Sandbox::new(config.install_root())?;
+        // safe because entering the sandbox only makes system calls, see Sandbox::enter
+        unsafe {
+            command.pre_exec(move || sandbox.enter());
+        }
+    }
+    let mut child = command.spawn()
+        .map_err(|e| std::io::Error::other(format!("Could not run {} of {}: {}", script.file_name(), package, e)))?;
+
+    let log = Arc::new(Mutex::new(log));
+    let stdout = copy_output(child.stdout.take().unwrap(), log.clone(), config.verbose(), false);
+    let stderr = copy_output(child.stderr.take().unwrap(), log.clone(), config.verbose(), true);
+    let status = wait_with_timeout(&mut child, config.scripts().timeout);
+    finish_output([stdout, stderr]);
+
+    match status? {
+        Some(status) => {
+            writeln!(log.lock().unwrap(), "==> {}", status)?;
+            if !status.success() {
+                return Err(std::io::Error::other(format!("{} of {} failed with {}, its output is in {}", script.file_name(), package, status, log_path)));
+            }
+        }
+        None => {
+            let timeout = config.scripts().timeout;
+            writeln!(log.lock().unwrap(), "==> killed after {} seconds", timeout)?;
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut,
+                format!("{} of {} did not finish within {} seconds, its output is in {}", script.file_name(), package, timeout, log_path)));
+        }
+    }
+    Ok(())
+}
+
+// USPM_PKG_OPERATION for a script run going from `old_version` to `new_version`
+fn operation(old_version: Option<&str>, new_version: Option<&str>) -> &'static str {
+    match (old_version, new_version) {
+        (_, None) => "remove",
+        (None, Some(_)) => "install",
+        (Some(old), Some(new)) if old == new => "reinstall",
+        (Some(_), Some(_)) => "upgrade",
+    }
+}
+
+// waits for a script to exit, or kills its process group once it has run for `timeout` seconds
+// (0 waits as long as it takes) and returns None
+fn wait_with_timeout(child: &mut std::process::Child, timeout: u64) -> Result<Option<ExitStatus>, std::io::Error> {
+    if timeout == 0 {
+        return child.wait().map(Some);
+    }
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    child.wait()?;
+    Ok(None)
+}
+
+// waits a moment for the output copying threads to reach the end of the script's output. a process the
+// script started in the background can keep its output open long after it exited, so threads that are
+// still copying after DRAIN_TIMEOUT are left to log whatever that process writes while uspm runs
+fn finish_output(threads: [JoinHandle<()>; 2]) {
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while threads.iter().any(|thread| !thread.is_finished()) && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    for thread in threads.into_iter().filter(|thread| thread.is_finished()) {
+        let _ = thread.join();
+    }
+}
+
+// copies a script's output into its log line by line, and onto the terminal as well when verbose
+fn copy_output<R: Read + Send + 'static>(output: R, log: Arc<Mutex<File>>, verbose: bool, stderr: bool) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        // read as bytes, a script is free to print things that aren't utf-8
+        for line in BufReader::new(output).split(b'\n').map_while(Result::ok) {
+            let mut line = line;
+            line.push(b'\n');
+            let _ = log.lock().unwrap().write_all(&line);
+            if verbose {
+                let _ = match stderr {
+                    true => std::io::stderr().write_all(&line),
+                    false => std::io::stdout().write_all(&line),
+                };
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_the_operation_from_the_versions() {
+        assert_eq!(operation(None, Some("1.0.0")), "install");
+        assert_eq!(operation(Some("1.0.0"), Some("2.0.0")), "upgrade");
+        assert_eq!(operation(Some("2.0.0"), Some("1.0.0")), "upgrade");
+        assert_eq!(operation(Some("1.0.0"), Some("1.0.0")), "reinstall");
+        assert_eq!(operation(Some("1.0.0"), None), "remove");
+    }
+}
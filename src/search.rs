@@ -0,0 +1,194 @@
+use regex::{Regex, RegexBuilder};
+use crate::package::{PackageFile, Packages};
+use crate::repo::{merge_repos, Repo};
+
+/// How search patterns are matched against package names and descriptions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchMode {
+    /// Case insensitive substring match, the default.
+    Substring,
+    /// Shell style glob (`*`, `?` and `[...]`) matched against the whole name or description.
+    Glob,
+    /// Case insensitive regular expression.
+    Regex,
+}
+
+pub struct SearchQuery {
+    pub patterns: Vec<String>,
+    pub mode: MatchMode,
+    pub license: Option<String>,
+    pub arch: Option<String>,
+    // Some(true) for only installed packages, Some(false) for only packages that aren't installed
+    pub installed: Option<bool>,
+}
+
+pub struct SearchResult {
+    pub mirror: String,
+    pub package: PackageFile,
+    pub installed_version: Option<String>,
+    // lower is better: exact name, name prefix, name match, then description only
+    rank: u8,
+}
+
+// translates a glob into an anchored regex
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut in_class = false;
+    for c in glob.chars() {
+        match c {
+            '*' if !in_class => regex.push_str(".*"),
+            '?' if !in_class => regex.push('.'),
+            '[' if !in_class => {
+                in_class = true;
+                regex.push('[');
+            }
+            ']' if in_class => {
+                in_class = false;
+                regex.push(']');
+            }
+            '!' if in_class && regex.ends_with('[') => regex.push('^'),
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+fn compile_pattern(pattern: &str, mode: MatchMode) -> Result<Regex, std::io::Error> {
+    let source = match mode {
+        MatchMode::Substring => regex::escape(pattern),
+        MatchMode::Glob => glob_to_regex(pattern),
+        MatchMode::Regex => pattern.to_string(),
+    };
+    RegexBuilder::new(&source)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid search pattern {}: {}", pattern, e)))
+}
+
+// returns how well a package matches a pattern, or None if it doesn't match at all
+fn rank_match(package: &PackageFile, pattern: &str, regex: &Regex) -> Option<u8> {
+    if package.name.eq_ignore_ascii_case(pattern) {
+        return Some(0);
+    }
+    if let Some(found) = regex.find(&package.name) {
+        return Some(if found.start() == 0 { 1 } else { 2 });
+    }
+    if regex.is_match(&package.description) {
+        return Some(3);
+    }
+    None
+}
+
+/// Searches the merged index of all the repos, keeping only the newest version of each package.
+/// Results are ranked by how closely they match, then sorted by name.
+pub fn search_packages(repos: &[(String, Repo)], installed: &Packages, query: &SearchQuery) -> Result<Vec<SearchResult>, std::io::Error> {
+    let mut patterns = Vec::new();
+    for pattern in query.patterns.iter() {
+        patterns.push((pattern.as_str(), compile_pattern(pattern, query.mode)?));
+    }
+
+    let mut results = Vec::new();
+    for (mirror, package) in merge_repos(repos) {
+        if let Some(license) = &query.license {
+            if !package.license.eq_ignore_ascii_case(license) {
+                continue;
+            }
+        }
+        if let Some(arch) = &query.arch {
+            // packages without an architecture run anywhere
+            if !(package.arch.is_empty() || package.arch == "any" || package.arch == *arch) {
+                continue;
+            }
+        }
+        let installed_version = installed.get_package(package.name.clone()).map(|p| p.version.clone());
+        if let Some(want_installed) = query.installed {
+            if installed_version.is_some() != want_installed {
+                continue;
+            }
+        }
+
+        // with no patterns every package matches, which makes the filters usable on their own
+        let rank = if patterns.is_empty() {
+            Some(0)
+        } else {
+            patterns.iter().filter_map(|(pattern, regex)| rank_match(&package, pattern, regex)).min()
+        };
+        if let Some(rank) = rank {
+            results.push(SearchResult { mirror, package, installed_version, rank });
+        }
+    }
+
+    results.sort_by(|a, b| a.rank.cmp(&b.rank).then_with(|| a.package.name.cmp(&b.package.name)));
+    Ok(results)
+}
+
+// number of single character insertions, deletions and substitutions to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Finds the names in `candidates` closest to `name`, for "did you mean" suggestions.
+/// Packages that provide `name` come first, then names within a small edit distance.
+pub fn suggest_packages(name: &str, candidates: &[PackageFile]) -> Vec<String> {
+    let max_distance = (name.chars().count() / 3).max(2);
+    let mut suggestions: Vec<(usize, String)> = Vec::new();
+    for candidate in candidates {
+        let distance = if candidate.provides.iter().any(|p| p == name) {
+            0
+        } else {
+            let name_distance = edit_distance(&name.to_lowercase(), &candidate.name.to_lowercase());
+            let provides_distance = candidate.provides.iter()
+                .map(|p| edit_distance(&name.to_lowercase(), &p.to_lowercase()))
+                .min()
+                .unwrap_or(usize::MAX);
+            name_distance.min(provides_distance)
+        };
+        if distance <= max_distance {
+            suggestions.push((distance, candidate.name.clone()));
+        }
+    }
+    suggestions.sort();
+    suggestions.dedup_by(|a, b| a.1 == b.1);
+    suggestions.into_iter().take(3).map(|(_, name)| name).collect()
+}
+
+/// Builds a not found error for `name` that suggests the closest of `candidates`.
+pub fn package_not_found(name: &str, candidates: &[PackageFile], message: &str) -> std::io::Error {
+    let suggestions = suggest_packages(name, candidates);
+    let message = if suggestions.is_empty() {
+        format!("Package {} {}", name, message)
+    } else {
+        format!("Package {} {}. Did you mean: {}?", name, message, suggestions.join(", "))
+    };
+    std::io::Error::new(std::io::ErrorKind::NotFound, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_globs() {
+        assert_eq!(glob_to_regex("lib*"), "^lib.*$");
+        assert_eq!(glob_to_regex("[!a]?"), "^[^a].$");
+        assert_eq!(glob_to_regex("a.b"), "^a\\.b$");
+    }
+
+    #[test]
+    fn counts_edits() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("same", "same"), 0);
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use reqwest::{Certificate, Identity, Proxy, RequestBuilder, Url};
+use tokio::sync::Mutex;
+use crate::config::{Config, MirrorAuth, NetworkConfig};
+use crate::repo::{download_repo_file, Repo};
+
+/// Everything a single command shares while it talks to the mirrors: the config,
+/// one pooled HTTP client, and each mirror's repo file, which is only downloaded once.
+pub struct Session {
+    config: Config,
+    client: reqwest::Client,
+    // keyed by repository name, None marks a repository that couldn't be reached so it isn't retried for every package
+    repos: Mutex<HashMap<String, Option<(String, Repo)>>>,
+}
+
+// splits a no_proxy entry into its host and port, if it has one. ipv6 addresses only have a port in brackets
+fn split_port(entry: &str) -> Option<(&str, Option<u16>)> {
+    let (host, port) = match entry.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']')?;
+            (host, rest.strip_prefix(':'))
+        }
+        None if entry.matches(':').count() == 1 => {
+            let (host, port) = entry.split_once(':').unwrap();
+            (host, Some(port))
+        }
+        None => (entry, None),
+    };
+    match port {
+        Some(port) => port.parse().ok().map(|port| (host, Some(port))),
+        None => Some((host, None)),
+    }
+}
+
+// whether the no_proxy list says `host` should be reached directly. an entry matches the host and
+// its subdomains, with or without a leading dot, and only that port if it has one. * matches everything
+fn bypasses_proxy(no_proxy: &[String], host: &str, port: Option<u16>) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.').to_lowercase();
+    no_proxy.iter().any(|entry| {
+        let entry = entry.trim().to_lowercase();
+        if entry == "*" {
+            return true;
+        }
+        let Some((domain, entry_port)) = split_port(&entry) else {
+            return false;
+        };
+        let domain = domain.trim_start_matches('.').trim_end_matches('.');
+        if domain.is_empty() || entry_port.is_some_and(|entry_port| Some(entry_port) != port) {
+            return false;
+        }
+        host == domain || host.ends_with(&(".".to_string() + domain))
+    })
+}
+
+// whether `url` is on `mirror`: the same scheme, host and port, and a path at or below the mirror's
+fn is_on_mirror(url: &Url, mirror: &Url) -> bool {
+    let mirror_path = mirror.path().trim_end_matches('/');
+    url.scheme() == mirror.scheme()
+        && url.host_str().map(|h| h.to_lowercase()) == mirror.host_str().map(|h| h.to_lowercase())
+        && url.port_or_known_default() == mirror.port_or_known_default()
+        && url.path().strip_prefix(mirror_path).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn parse_proxy(proxy: &Option<String>) -> Result<Option<Url>, std::io::Error> {
+    match proxy {
+        Some(proxy) => Url::parse(proxy)
+            .map(Some)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid proxy {}: {}", proxy, e))),
+        None => Ok(None),
+    }
+}
+
+// builds the HTTP client used for every request to the mirrors
+fn build_client(network: &NetworkConfig) -> Result<reqwest::Client, std::io::Error> {
+    let mut builder = reqwest::Client::builder();
+
+    let http_proxy = parse_proxy(&network.http_proxy)?;
+    let https_proxy = parse_proxy(&network.https_proxy)?;
+    if http_proxy.is_some() || https_proxy.is_some() {
+        let no_proxy = network.no_proxy.clone();
+        builder = builder.proxy(Proxy::custom(move |url| {
+            if bypasses_proxy(&no_proxy, url.host_str().unwrap_or(""), url.port_or_known_default()) {
+                return None;
+            }
+            match url.scheme() {
+                "https" => https_proxy.clone(),
+                _ => http_proxy.clone(),
+            }
+        }));
+    }
+
+    for path in network.ca_certificates.iter() {
+        let pem = std::fs::read(path)?;
+        let certificate = Certificate::from_pem(&pem)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid CA certificate {}: {}", path, e)))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+
+    if let Some(path) = &network.client_certificate {
+        let der = std::fs::read(path)?;
+        let identity = Identity::from_pkcs12_der(&der, &network.client_certificate_password)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid client certificate {}: {}", path, e)))?;
+        builder = builder.identity(identity);
+    }
+
+    builder.build().map_err(std::io::Error::other)
+}
+
+/// Adds the mirror's credentials, if it has any, to a request.
+pub fn apply_auth(request: RequestBuilder, auth: Option<&MirrorAuth>) -> RequestBuilder {
+    match auth {
+        Some(MirrorAuth::Basic { username, password }) => request.basic_auth(username, Some(password)),
+        Some(MirrorAuth::Bearer { token }) => request.bearer_auth(token),
+        None => request,
+    }
+}
+
+// the credentials of the mirror `url` is on. when mirrors are nested the most specific one wins
+fn auth_for<'a>(mirror_auth: &'a HashMap<String, MirrorAuth>, url: &str) -> Option<&'a MirrorAuth> {
+    let url = Url::parse(url).ok()?;
+    mirror_auth.iter()
+        .filter_map(|(mirror, auth)| Url::parse(mirror).ok().map(|mirror| (mirror, auth)))
+        .filter(|(mirror, _)| is_on_mirror(&url, mirror))
+        .max_by_key(|(mirror, _)| mirror.path().trim_end_matches('/').len())
+        .map(|(_, auth)| auth)
+}
+
+impl Session {
+    /// Sets up a session, failing if the network settings in the config can't be used
+    /// (an invalid proxy url or an unreadable certificate, for example).
+    pub fn new(config: Config) -> Result<Self, std::io::Error> {
+        let client = build_client(config.network())?;
+        Ok(Session {
+            config,
+            client,
+            repos: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Finds the credentials for the mirror that `url` is on.
+    pub fn auth_for(&self, url: &str) -> Option<&MirrorAuth> {
+        auth_for(&self.config.network().mirror_auth, url)
+    }
+
+    /// Starts a GET request to `url` with the credentials of its mirror.
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        apply_auth(self.client.get(url), self.auth_for(url))
+    }
+
+    /// Gets the repo file of every enabled repository, downloading any that haven't been fetched yet.
+    /// Each repository's mirrors are tried in order until one works, and repositories that can't be
+    /// reached on any mirror are skipped. The url the repo came from is kept alongside it so packages
+    /// can be fetched from the same place later.
+    pub async fn repos(&self) -> Vec<(String, Repo)> {
+        let mut cache = self.repos.lock().await;
+        let mut repos = Vec::new();
+        for repository in self.config.repositories() {
+            if !cache.contains_key(&repository.name) {
+                let mut found = None;
+                for mirror in repository.mirrors.iter() {
+                    let base_url = repository.base_url(mirror);
+                    match download_repo_file(self, &base_url, repository.signing_key.as_ref()).await {
+                        Ok(mut repo) => {
+                            repo.name = repository.name.clone();
+                            repo.priority = repository.priority;
+                            found = Some((base_url, repo));
+                            break;
+                        }
+                        Err(e) => println!("Could not download repo file from {}: {}", base_url, e),
+                    }
+                }
+                cache.insert(repository.name.clone(), found);
+            }
+            if let Some(found) = cache.get(&repository.name).unwrap() {
+                repos.push(found.clone());
+            }
+        }
+        repos
+    }
+    /// The enabled repositories that couldn't be reached on any mirror by `repos`.
+    pub async fn unreachable_repositories(&self) -> Vec<String> {
+        let cache = self.repos.lock().await;
+        self.config.repositories().into_iter()
+            .map(|repository| repository.name)
+            .filter(|name| matches!(cache.get(name), Some(None)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_only_goes_to_its_own_mirror() {
+        let token = |token: &str| MirrorAuth::Bearer { token: token.to_string() };
+        let mirror_auth: HashMap<String, MirrorAuth> = [
+            ("https://repo.example.com".to_string(), token("root")),
+            ("https://repo.example.com/private/".to_string(), token("private")),
+        ].into_iter().collect();
+        let found = |url: &str| match auth_for(&mirror_auth, url) {
+            Some(MirrorAuth::Bearer { token }) => Some(token.as_str()),
+            _ => None,
+        };
+
+        assert_eq!(found("https://repo.example.com/app.uspm"), Some("root"));
+        assert_eq!(found("https://REPO.example.com:443/app.uspm"), Some("root"));
+        assert_eq!(found("https://repo.example.com/private/app.uspm"), Some("private"));
+        assert_eq!(found("https://repo.example.com/private2/app.uspm"), Some("root"));
+        assert_eq!(found("https://repo.example.com.evil.net/app.uspm"), None);
+        assert_eq!(found("https://repo.example.com:8443/app.uspm"), None);
+        assert_eq!(found("http://repo.example.com/app.uspm"), None);
+    }
+
+    #[test]
+    fn no_proxy_matches_hosts_subdomains_and_ports() {
+        let no_proxy: Vec<String> = ["example.com", ".internal", "mirror.lan:8080", "[::1]:80"].iter().map(|e| e.to_string()).collect();
+        assert!(bypasses_proxy(&no_proxy, "example.com", Some(443)));
+        assert!(bypasses_proxy(&no_proxy, "repo.Example.com", Some(80)));
+        assert!(!bypasses_proxy(&no_proxy, "badexample.com", Some(80)));
+        assert!(bypasses_proxy(&no_proxy, "internal", Some(80)));
+        assert!(bypasses_proxy(&no_proxy, "repo.internal", Some(80)));
+        assert!(bypasses_proxy(&no_proxy, "mirror.lan", Some(8080)));
+        assert!(!bypasses_proxy(&no_proxy, "mirror.lan", Some(80)));
+        assert!(bypasses_proxy(&no_proxy, "[::1]", Some(80)));
+        assert!(!bypasses_proxy(&no_proxy, "other.org", Some(80)));
+        assert!(bypasses_proxy(&["*".to_string()], "other.org", Some(80)));
+    }
+}